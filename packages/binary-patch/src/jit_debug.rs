@@ -0,0 +1,249 @@
+//! Telling debuggers about patch code through the GDB JIT interface
+//!
+//! Code we map ourselves is invisible to debuggers, so backtraces through a patch show bare
+//! addresses. gdb and lldb both put a breakpoint on `__jit_debug_register_code` and walk
+//! `__jit_debug_descriptor` when it's hit, so for every patch we build a small in-memory ELF
+//! describing its functions (plus its DWARF, when the objects carry any) and link it in there.
+
+use object::{
+    elf,
+    write::{
+        elf::{FileHeader, SectionHeader, Sym, Writer},
+        StringId,
+    },
+    Endianness,
+};
+use std::{ptr, sync::Mutex};
+
+#[repr(C)]
+pub struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+const JIT_REGISTER_FN: u32 = 1;
+
+/// Read by the debugger whenever `__jit_debug_register_code` is called
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// The debugger breaks here, so it must stay an actual call
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    std::hint::black_box(());
+}
+
+/// Serializes updates to the descriptor
+static REGISTER: Mutex<()> = Mutex::new(());
+
+/// What a debugger needs to know about a loaded patch
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    /// Where the patch's code lives, as (start, len)
+    pub text: (usize, usize),
+
+    /// Functions as (name, address, size)
+    pub functions: Vec<(String, usize, usize)>,
+
+    /// `.debug_*` sections, already relocated to the runtime addresses
+    pub dwarf: Vec<(String, Vec<u8>)>,
+}
+
+/// Hand a patch to any attached debugger, returning its entry in the debugger's list. The image is
+/// leaked, since the debugger may read it at any point.
+pub fn register(info: &DebugInfo) -> anyhow::Result<*const JitCodeEntry> {
+    let image: &'static [u8] = Box::leak(build_image(info)?.into_boxed_slice());
+    let entry = Box::leak(Box::new(JitCodeEntry {
+        next_entry: ptr::null_mut(),
+        prev_entry: ptr::null_mut(),
+        symfile_addr: image.as_ptr(),
+        symfile_size: image.len() as u64,
+    }));
+
+    let _guard = REGISTER.lock().unwrap();
+    unsafe {
+        let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+        entry.next_entry = (*descriptor).first_entry;
+        if let Some(first) = entry.next_entry.as_mut() {
+            first.prev_entry = entry;
+        }
+        (*descriptor).first_entry = entry;
+        (*descriptor).relevant_entry = entry;
+        (*descriptor).action_flag = JIT_REGISTER_FN;
+    }
+    __jit_debug_register_code();
+
+    Ok(entry)
+}
+
+/// An ELF with a `.text` placed where the patch's code actually is, its function symbols, and
+/// its DWARF
+fn build_image(info: &DebugInfo) -> anyhow::Result<Vec<u8>> {
+    let mut image = Vec::new();
+    let mut writer = Writer::new(Endianness::default(), true, &mut image);
+    writer.reserve_file_header();
+
+    writer.reserve_null_section_index();
+    let text_name = writer.add_section_name(b".text");
+    let text = writer.reserve_section_index();
+
+    let dwarf = info
+        .dwarf
+        .iter()
+        .map(|(name, data)| {
+            let name = writer.add_section_name(name.as_bytes());
+            writer.reserve_section_index();
+            (name, data)
+        })
+        .collect::<Vec<_>>();
+
+    writer.reserve_null_symbol_index();
+    let functions = info
+        .functions
+        .iter()
+        .map(|(name, addr, size)| {
+            let name = writer.add_string(name.as_bytes());
+            writer.reserve_symbol_index(Some(text));
+            (name, *addr, *size)
+        })
+        .collect::<Vec<(StringId, usize, usize)>>();
+
+    writer.reserve_symtab_section_index();
+    writer.reserve_strtab_section_index();
+    writer.reserve_shstrtab_section_index();
+
+    let dwarf_offsets = dwarf
+        .iter()
+        .map(|(_, data)| writer.reserve(data.len(), 1))
+        .collect::<Vec<_>>();
+    writer.reserve_symtab();
+    writer.reserve_strtab();
+    writer.reserve_shstrtab();
+    writer.reserve_section_headers();
+
+    writer.write_file_header(&FileHeader {
+        os_abi: elf::ELFOSABI_NONE,
+        abi_version: 0,
+        e_type: elf::ET_EXEC,
+        e_machine: MACHINE,
+        e_entry: 0,
+        e_flags: 0,
+    })?;
+
+    for (_, data) in dwarf.iter() {
+        writer.write(data);
+    }
+
+    writer.write_null_symbol();
+    for (name, addr, size) in functions.iter() {
+        writer.write_symbol(&Sym {
+            name: Some(*name),
+            section: Some(text),
+            st_info: (elf::STB_GLOBAL << 4) | elf::STT_FUNC,
+            st_other: elf::STV_DEFAULT,
+            st_shndx: 0,
+            st_value: *addr as u64,
+            st_size: *size as u64,
+        });
+    }
+    writer.write_strtab();
+    writer.write_shstrtab();
+
+    writer.write_null_section_header();
+
+    // The code is already in memory, so the section only says where
+    writer.write_section_header(&SectionHeader {
+        name: Some(text_name),
+        sh_type: elf::SHT_NOBITS,
+        sh_flags: u64::from(elf::SHF_ALLOC | elf::SHF_EXECINSTR),
+        sh_addr: info.text.0 as u64,
+        sh_offset: 0,
+        sh_size: info.text.1 as u64,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 16,
+        sh_entsize: 0,
+    });
+    for ((name, data), offset) in dwarf.iter().zip(dwarf_offsets) {
+        writer.write_section_header(&SectionHeader {
+            name: Some(*name),
+            sh_type: elf::SHT_PROGBITS,
+            sh_flags: 0,
+            sh_addr: 0,
+            sh_offset: offset as u64,
+            sh_size: data.len() as u64,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 1,
+            sh_entsize: 0,
+        });
+    }
+    writer.write_symtab_section_header(1);
+    writer.write_strtab_section_header();
+    writer.write_shstrtab_section_header();
+
+    Ok(image)
+}
+
+#[cfg(target_arch = "aarch64")]
+const MACHINE: u16 = elf::EM_AARCH64;
+#[cfg(not(target_arch = "aarch64"))]
+const MACHINE: u16 = elf::EM_X86_64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSection, ObjectSymbol};
+
+    #[test]
+    fn registers_patches_with_the_debugger() {
+        let info = DebugInfo {
+            text: (0x7000_0000, 0x100),
+            functions: vec![
+                ("harness::app".to_string(), 0x7000_0000, 0x40),
+                ("harness::helper".to_string(), 0x7000_0040, 0x20),
+            ],
+            dwarf: vec![(".debug_line".to_string(), vec![1, 2, 3, 4])],
+        };
+
+        let image = build_image(&info).unwrap();
+        let file = object::File::parse(&*image).unwrap();
+        let text = file.section_by_name(".text").unwrap();
+        assert_eq!((text.address(), text.size()), (0x7000_0000, 0x100));
+        assert_eq!(
+            file.section_by_name(".debug_line").unwrap().data().unwrap(),
+            [1, 2, 3, 4]
+        );
+        let helper = file.symbol_by_name("harness::helper").unwrap();
+        assert_eq!((helper.address(), helper.size()), (0x7000_0040, 0x20));
+
+        // Each registration goes on the front of the list. Other tests load patches too, so only
+        // look at the list with the lock held and only at our own entries.
+        let older = register(&info).unwrap();
+        let newer = register(&info).unwrap();
+        let _guard = REGISTER.lock().unwrap();
+        unsafe {
+            let descriptor = &*ptr::addr_of!(__jit_debug_descriptor);
+            assert_eq!(descriptor.action_flag, JIT_REGISTER_FN);
+            assert_eq!((*newer).next_entry.cast_const(), older);
+            assert_eq!((*older).prev_entry.cast_const(), newer);
+            assert_eq!((*newer).symfile_size, image.len() as u64);
+        }
+    }
+}
@@ -3,44 +3,312 @@ use dioxus::prelude::*;
 use libloading::Library;
 use memmap::MmapOptions;
 use object::{Object, ObjectSymbol};
-use std::{collections::HashMap, env, fs, path::PathBuf};
-use tokio::io::AsyncBufReadExt;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CString,
+    fs,
+    io::BufRead,
+    path::PathBuf,
+    sync::{Arc, Mutex, Once},
+};
 
 pub use hotreload_macro::hotreload_start as start;
 
-/// Waits for stdin to send a new library
-pub fn use_hotreload_component(name: &str, initial: fn() -> Element) -> Element {
-    let mut library = use_signal(|| None as Option<&'static mut Library>);
-    let mut libraries = use_signal(|| vec![]);
+mod jit_debug;
+mod near;
+mod objects;
+
+/// A patch from the driver: the library with the new code and the hot functions it affects
+#[derive(Deserialize)]
+struct Patch {
+    library: PathBuf,
+    affected: Vec<String>,
+
+    /// The patch's relocatable objects, for loading without going through the linked library
+    #[serde(default)]
+    objects: Vec<PathBuf>,
+}
+
+/// A loaded patch: its objects loaded next to the binary, or the linked library wherever `dlopen`
+/// put it
+enum PatchLibrary {
+    Objects(objects::ObjectImage),
+    Dlopen(Library),
+}
+
+impl PatchLibrary {
+    /// Prefer loading the patch's objects next to the binary, and fall back to `dlopen` for
+    /// anything the object loader can't handle
+    fn load(patch: &Patch) -> anyhow::Result<Self> {
+        if !patch.objects.is_empty() {
+            match objects::ObjectImage::load(&patch.objects, &resolve) {
+                Ok(image) => return Ok(Self::Objects(image)),
+                Err(err) => eprintln!("loading the linked patch instead of its objects: {err}"),
+            }
+        }
+
+        Ok(Self::Dlopen(unsafe { Library::new(&patch.library)? }))
+    }
+
+    fn symbol(&self, name: &str) -> Option<*const ()> {
+        match self {
+            Self::Objects(image) => image.get(name),
+            Self::Dlopen(lib) => unsafe { lib.get::<*const ()>(name.as_bytes()).ok().map(|f| *f) },
+        }
+    }
+
+    fn component(&self, name: &str) -> Option<unsafe extern "C" fn() -> Element> {
+        self.symbol(name)
+            .map(|addr| unsafe { std::mem::transmute::<*const (), _>(addr) })
+    }
+}
 
+/// Every patch loaded so far, oldest first
+static GENERATIONS: Mutex<Vec<&'static PatchLibrary>> = Mutex::new(Vec::new());
+
+/// Find a symbol for a new patch: the latest patch defining it wins, then the app itself
+fn resolve(name: &str) -> Option<usize> {
+    let from_patches = GENERATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find_map(|lib| lib.symbol(name));
+    if let Some(addr) = from_patches {
+        return Some(addr as usize);
+    }
+
+    let name = CString::new(name).ok()?;
+    let addr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    (!addr.is_null()).then_some(addr as usize)
+}
+
+/// Hot function -> the library holding its latest version. Libraries are leaked since code from
+/// them might still be on the stack of another thread.
+static PATCHED: Mutex<Option<HashMap<String, &'static PatchLibrary>>> = Mutex::new(None);
+
+/// Hot function -> how to re-render each component running it, by the component's scope
+static MOUNTED: Mutex<Option<HashMap<String, Vec<(ScopeId, Update)>>>> = Mutex::new(None);
+
+type Update = Arc<dyn Fn() + Send + Sync>;
+
+static LISTEN: Once = Once::new();
+
+/// Reserve the region for patches as the process starts. By the first render the runtime and the
+/// renderer have already mapped whatever they like around the binary.
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+#[cfg_attr(target_os = "macos", link_section = "__DATA,__mod_init_func")]
+static RESERVE_NEAR_REGION: extern "C" fn() = {
+    extern "C" fn reserve() {
+        near::reserve();
+    }
+    reserve
+};
+
+/// Renders the latest version of a hot function, re-rendering whenever a patch affects it
+pub fn use_hotreload_component(name: &str, initial: fn() -> Element) -> Element {
+    let scope = current_scope_id().expect("use_hotreload_component must be called in a component");
     use_hook(|| {
-        spawn(async move {
-            let stdin = tokio::io::stdin();
-            let stdin = tokio::io::BufReader::new(stdin);
-            let mut lines = stdin.lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                let so = PathBuf::from(line);
-
-                // we *need* to leak the library otherwise it will cause issues with the process not exiting properly
-                let lib = unsafe { libloading::Library::new(so).ok().unwrap() };
-                let old = library.replace(Some(Box::leak(Box::new(lib))));
-
-                // don't forget the old library - but require its drop to be called
-                if let Some(old) = old {
-                    libraries.write().push(old);
+        MOUNTED
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .entry(name.to_string())
+            .or_default()
+            .push((scope, schedule_update()));
+
+        LISTEN.call_once(|| {
+            std::thread::spawn(listen_for_patches);
+        });
+    });
+
+    // Patches shouldn't keep scheduling updates for a component that's gone
+    let owner = name.to_string();
+    use_drop(move || {
+        if let Some(mounted) = MOUNTED.lock().unwrap().as_mut() {
+            if let Some(updates) = mounted.get_mut(&owner) {
+                updates.retain(|(mounted, _)| *mounted != scope);
+                if updates.is_empty() {
+                    mounted.remove(&owner);
                 }
             }
-        })
+        }
     });
 
-    library.with(|f| {
-        if let Some(lib) = f {
-            unsafe {
-                lib.get::<unsafe extern "C" fn() -> Element>(name.as_bytes())
-                    .unwrap()()
+    let patched = PATCHED
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|p| p.get(name).copied());
+
+    match patched {
+        Some(lib) => unsafe { lib.component(name).unwrap()() },
+        None => initial(),
+    }
+}
+
+/// Tell the driver whether a patch made it in, so it knows what later patches can link against
+fn acknowledge(patch: &Patch, error: Option<String>) {
+    let ack = serde_json::json!({ "library": patch.library, "error": error });
+    println!("[hotreload] {ack}");
+}
+
+/// Read patches off stdin for the lifetime of the app and mark just the affected components dirty
+fn listen_for_patches() {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        let patch: Patch = match serde_json::from_str(&line) {
+            Ok(patch) => patch,
+            Err(err) => {
+                eprintln!("bad patch message {line:?}: {err}");
+                continue;
+            }
+        };
+
+        // we *need* to leak the library otherwise it will cause issues with the process not exiting properly
+        let lib = match PatchLibrary::load(&patch) {
+            Ok(lib) => &*Box::leak(Box::new(lib)),
+            Err(err) => {
+                eprintln!("failed to load {:?}: {err}", patch.library);
+                acknowledge(&patch, Some(err.to_string()));
+                continue;
             }
-        } else {
-            initial()
+        };
+        acknowledge(&patch, None);
+        GENERATIONS.lock().unwrap().push(lib);
+
+        // Only functions the library actually carries can be swapped in - the rest keep running
+        // whatever version they had
+        {
+            let mut patched = PATCHED.lock().unwrap();
+            let patched = patched.get_or_insert_with(HashMap::new);
+            for name in patch.affected.iter() {
+                if lib.component(name).is_some() {
+                    patched.insert(name.clone(), lib);
+                }
+            }
+        }
+
+        let mounted = MOUNTED.lock().unwrap();
+        for name in patch.affected.iter() {
+            for (_, update) in mounted
+                .as_ref()
+                .and_then(|m| m.get(name))
+                .into_iter()
+                .flatten()
+            {
+                update();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    /// Patches as the driver sends them make it to the loaders, objects first
+    #[test]
+    fn loads_patches_the_driver_sends() {
+        let object =
+            objects::tests::write_object(&objects::tests::answer_object(), "patch-message");
+        let library = object.with_extension("so");
+        let linked = std::process::Command::new("cc")
+            .args(["-shared", "-nostdlib", "-o"])
+            .arg(&library)
+            .arg(&object)
+            .output()
+            .unwrap();
+        assert!(linked.status.success(), "{linked:?}");
+
+        let answer = |lib: &PatchLibrary| {
+            let answer: extern "C" fn() -> i32 =
+                unsafe { std::mem::transmute(lib.symbol("answer").unwrap()) };
+            answer()
+        };
+
+        let message = serde_json::json!({
+            "library": library,
+            "affected": ["harness::app"],
+            "objects": [object],
+        });
+        let patch: Patch = serde_json::from_str(&message.to_string()).unwrap();
+        let lib = PatchLibrary::load(&patch).unwrap();
+        assert!(matches!(lib, PatchLibrary::Objects(_)));
+        assert_eq!(answer(&lib), 43);
+
+        // Without objects the linked library goes through dlopen
+        let message = serde_json::json!({ "library": library, "affected": ["harness::app"] });
+        let patch: Patch = serde_json::from_str(&message.to_string()).unwrap();
+        let lib = PatchLibrary::load(&patch).unwrap();
+        assert!(matches!(lib, PatchLibrary::Dlopen(_)));
+        assert_eq!(answer(&lib), 43);
+
+        fs::remove_file(&object).unwrap();
+        fs::remove_file(&library).unwrap();
+    }
+
+    /// A panic in the app makes it back out through a patch's frames, whichever way it was loaded
+    #[test]
+    fn panics_unwind_through_patches() {
+        let dir = std::env::temp_dir().join(format!("binary-patch-unwind-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // The call isn't in tail position, so the patch's frame is on the stack when `f` panics
+        let source = dir.join("unwind.c");
+        fs::write(
+            &source,
+            "int call_through(void (*f)(void)) { f(); return 1; }\n",
+        )
+        .unwrap();
+        let object = dir.join("unwind.o");
+        let library = dir.join("unwind.so");
+        let cc = |args: &[&std::ffi::OsStr]| {
+            let built = std::process::Command::new("cc")
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(built.status.success(), "{built:?}");
+        };
+        cc(&[
+            "-c".as_ref(),
+            "-O0".as_ref(),
+            "-fPIC".as_ref(),
+            "-funwind-tables".as_ref(),
+            "-o".as_ref(),
+            object.as_ref(),
+            source.as_ref(),
+        ]);
+        cc(&[
+            "-shared".as_ref(),
+            "-nostdlib".as_ref(),
+            "-o".as_ref(),
+            library.as_ref(),
+            object.as_ref(),
+        ]);
+
+        extern "C-unwind" fn boom() {
+            panic!("boom");
         }
-    })
+
+        for objects in [vec![object.clone()], vec![]] {
+            let message = serde_json::json!({
+                "library": library,
+                "affected": [],
+                "objects": objects,
+            });
+            let patch: Patch = serde_json::from_str(&message.to_string()).unwrap();
+            let lib = PatchLibrary::load(&patch).unwrap();
+            let call_through: extern "C-unwind" fn(extern "C-unwind" fn()) -> i32 =
+                unsafe { std::mem::transmute(lib.symbol("call_through").unwrap()) };
+
+            let caught = std::panic::catch_unwind(|| call_through(boom));
+            assert_eq!(caught.unwrap_err().downcast_ref::<&str>(), Some(&"boom"));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,192 @@
+//! Address space right next to the original binary
+//!
+//! `dlopen` puts a patch wherever it likes, usually too far from the app for a `call rel32` or an
+//! `ADRP` to reach the original code. We reserve a block of address space within reach of the main
+//! binary at startup and hand patch memory out of it to [`crate::objects`], which relocates a
+//! patch's objects against the app directly. Linked patches still go through `dlopen`.
+
+use object::{Object, ObjectSegment};
+use std::sync::Mutex;
+
+/// How far a PC-relative reference can reach: `rel32` on x86_64, `ADRP` on aarch64
+#[cfg(target_arch = "aarch64")]
+pub const REACH: u64 = 4 << 30;
+#[cfg(not(target_arch = "aarch64"))]
+pub const REACH: u64 = 2 << 30;
+
+/// How much address space to set aside for patches. It's only reserved, not committed.
+const RESERVATION: usize = 256 << 20;
+
+static REGION: Mutex<Option<NearRegion>> = Mutex::new(None);
+
+/// Reserve the region next to the main binary. This runs from an initializer at process start,
+/// before other mappings take the space around the binary.
+pub fn reserve() {
+    let mut region = REGION.lock().unwrap();
+    if region.is_some() {
+        return;
+    }
+
+    match NearRegion::reserve(RESERVATION) {
+        Ok(reserved) => *region = Some(reserved),
+        Err(err) => eprintln!("no room next to the binary, falling back to dlopen: {err}"),
+    }
+}
+
+/// Take writable memory from the region next to the binary
+pub fn alloc(len: usize, align: usize) -> anyhow::Result<*mut u8> {
+    REGION
+        .lock()
+        .unwrap()
+        .as_mut()
+        .ok_or_else(|| anyhow::anyhow!("no region was reserved next to the binary"))?
+        .alloc(len, align)
+}
+
+/// Address space within [`REACH`] of every byte of the main binary, handed out front to back
+#[derive(Debug)]
+pub struct NearRegion {
+    start: usize,
+    len: usize,
+    used: usize,
+}
+
+// The region is only ever touched behind `REGION`'s lock
+unsafe impl Send for NearRegion {}
+
+impl NearRegion {
+    pub fn reserve(len: usize) -> anyhow::Result<Self> {
+        let (image_start, image_end) = main_image_extent()?;
+        let page = page_size::get();
+        let len = len.next_multiple_of(page);
+
+        // Every address in [low, high) reaches all of the binary
+        let low = image_end
+            .saturating_sub(REACH as usize)
+            .next_multiple_of(page);
+        let high = image_start.saturating_add(REACH as usize);
+
+        // Try just past the binary first, then just before it, moving outwards
+        let step = 16 << 20;
+        let above = (0..)
+            .map(|i| image_end.next_multiple_of(page) + step * i)
+            .take_while(|&hint| hint + len <= high);
+        let below = (1..)
+            .map_while(|i| (image_start & !(page - 1)).checked_sub(step * i + len))
+            .take_while(|&hint| hint >= low);
+
+        for hint in above.take(128).chain(below.take(128)) {
+            let Some(start) = map_at(hint, len) else {
+                continue;
+            };
+
+            if start >= low && start + len <= high {
+                return Ok(Self {
+                    start,
+                    len,
+                    used: 0,
+                });
+            }
+
+            unsafe { libc::munmap(start as *mut libc::c_void, len) };
+        }
+
+        anyhow::bail!(
+            "no free {len:#x} bytes within {REACH:#x} of the binary at {image_start:#x}..{image_end:#x}"
+        )
+    }
+
+    /// Carve `len` bytes off the region and make them writable
+    pub fn alloc(&mut self, len: usize, align: usize) -> anyhow::Result<*mut u8> {
+        let page = page_size::get();
+        let offset = (self.start + self.used).next_multiple_of(align.max(page)) - self.start;
+        let len = len.next_multiple_of(page);
+        anyhow::ensure!(
+            offset + len <= self.len,
+            "patch region is full ({:#x} of {:#x} bytes used)",
+            self.used,
+            self.len
+        );
+
+        let ptr = (self.start + offset) as *mut u8;
+        protect(ptr, len, libc::PROT_READ | libc::PROT_WRITE)?;
+        self.used = offset + len;
+        Ok(ptr)
+    }
+}
+
+/// Where the main binary sits in memory
+fn main_image_extent() -> anyhow::Result<(usize, usize)> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let found = unsafe { libc::dladdr(main_image_extent as *const libc::c_void, &mut info) };
+    anyhow::ensure!(found != 0, "couldn't find the main binary in memory");
+    let start = info.dli_fbase as usize;
+
+    // The headers don't say how much the image spans once mapped, so ask the file on disk
+    let data = std::fs::read(std::env::current_exe()?)?;
+    let file = object::File::parse(&*data)?;
+    let segments = file
+        .segments()
+        .filter(|s| s.name().ok().flatten() != Some("__PAGEZERO"))
+        .collect::<Vec<_>>();
+    let lowest = segments.iter().map(|s| s.address()).min().unwrap_or(0);
+    let highest = segments
+        .iter()
+        .map(|s| s.address() + s.size())
+        .max()
+        .unwrap_or(0);
+
+    Ok((start, start + (highest - lowest) as usize))
+}
+
+/// Reserve `len` bytes of inaccessible address space, preferably at `hint`
+fn map_at(hint: usize, len: usize) -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+    #[cfg(not(target_os = "linux"))]
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANON;
+
+    let addr = unsafe {
+        libc::mmap(
+            hint as *mut libc::c_void,
+            len,
+            libc::PROT_NONE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    (addr != libc::MAP_FAILED).then_some(addr as usize)
+}
+
+pub fn protect(ptr: *mut u8, len: usize, prot: libc::c_int) -> anyhow::Result<()> {
+    if unsafe { libc::mprotect(ptr as *mut libc::c_void, len, prot) } != 0 {
+        anyhow::bail!("mprotect failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserves_within_reach_of_the_binary() {
+        let (start, end) = main_image_extent().unwrap();
+        let mut region = NearRegion::reserve(64 << 20).unwrap();
+
+        let code = region.alloc(100, 1).unwrap() as usize;
+        let data = region.alloc(5000, 1 << 16).unwrap() as usize;
+        assert!(code >= region.start && data + 5000 <= region.start + region.len);
+        assert_eq!(data % (1 << 16), 0);
+        assert!(data >= code + page_size::get());
+
+        for addr in [code, data] {
+            assert!(addr.abs_diff(start) < REACH as usize);
+            assert!(addr.abs_diff(end) < REACH as usize);
+        }
+
+        // The memory is ours to write
+        unsafe { (data as *mut u64).write(0xdead_beef) };
+    }
+}
@@ -0,0 +1,788 @@
+//! Loading relocatable ELF objects straight into the app, without linking them first
+//!
+//! The objects' sections are copied into the region next to the binary, so most references back
+//! into the app can be patched in place. Anything a PC-relative call can't reach, and anything
+//! read through the GOT, goes through a table of stubs placed right after the code. Their DWARF is
+//! relocated too, so it can be handed to a debugger along with the code, and their `.eh_frame` is
+//! registered with the unwinder so panics can unwind through patch code.
+
+use crate::{jit_debug, near};
+use object::{
+    elf,
+    read::elf::{ElfFile64, ElfSection64, SectionHeader},
+    Architecture, Endianness, Object, ObjectSection, ObjectSymbol, RelocationFlags,
+    RelocationTarget, SectionIndex, SymbolIndex, SymbolSection,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    ptr,
+};
+
+/// One GOT entry plus the code to jump through it, so a far call can land on the trampoline and
+/// a GOT load can read `addr`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Stub {
+    addr: usize,
+    trampoline: [u8; 8],
+}
+
+impl Stub {
+    fn new(arch: Architecture, addr: usize) -> Self {
+        let trampoline = match arch {
+            // jmp *-0xe(%rip), landing back on `addr`
+            Architecture::X86_64 => [0xff, 0x25, 0xf2, 0xff, 0xff, 0xff, 0x00, 0x00],
+
+            // ldr x16, #-8 ; br x16
+            _ => {
+                let mut code = [0; 8];
+                code[..4].copy_from_slice(&0x58ff_ffd0u32.to_le_bytes());
+                code[4..].copy_from_slice(&0xd61f_0200u32.to_le_bytes());
+                code
+            }
+        };
+        Self { addr, trampoline }
+    }
+}
+
+/// Where each kind of section goes, grouped by the protection it ends up with
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Text,
+    ReadOnly,
+    Data,
+}
+
+/// A set of objects loaded into the app as one patch
+pub struct ObjectImage {
+    symbols: HashMap<String, usize>,
+}
+
+/// The objects of a patch while they're being laid out and relocated
+struct Loader<'data> {
+    arch: Architecture,
+    files: Vec<ElfFile64<'data, Endianness>>,
+
+    /// (file, section) -> runtime address
+    sections: HashMap<(usize, SectionIndex), usize>,
+
+    /// (file, symbol) -> runtime address of a common symbol
+    commons: HashMap<(usize, SymbolIndex), usize>,
+
+    /// Global definitions across all the objects
+    globals: HashMap<String, usize>,
+
+    stubs: *mut Stub,
+    stub_count: usize,
+    stub_for: HashMap<usize, *mut Stub>,
+
+    /// `.debug_*` sections of all the objects, concatenated by name
+    dwarf: BTreeMap<String, Vec<u8>>,
+
+    /// (file, section) -> where a debug section's bytes are in `dwarf`. Its "address" in
+    /// `sections` is its offset, which is what other debug sections refer to it by.
+    debug_sections: HashMap<(usize, SectionIndex), usize>,
+
+    /// Where each object's `.eh_frame` was loaded, each followed by a zero terminator
+    eh_frames: Vec<usize>,
+}
+
+extern "C" {
+    /// libgcc's unwinder takes a whole zero-terminated `.eh_frame` here
+    fn __register_frame(begin: *const u8);
+}
+
+impl ObjectImage {
+    /// Load the objects of one patch, resolving what they don't define themselves with `resolve`
+    pub fn load(
+        paths: &[PathBuf],
+        resolve: &dyn Fn(&str) -> Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let data = paths
+            .iter()
+            .map(std::fs::read)
+            .collect::<Result<Vec<_>, _>>()?;
+        let files = data
+            .iter()
+            .map(|data| ElfFile64::<Endianness>::parse(&**data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let arch = files
+            .first()
+            .map(|file| file.architecture())
+            .unwrap_or(Architecture::Unknown);
+        anyhow::ensure!(
+            matches!(arch, Architecture::X86_64 | Architecture::Aarch64),
+            "can't load {arch:?} objects"
+        );
+
+        let mut loader = Loader {
+            arch,
+            files,
+            sections: HashMap::new(),
+            commons: HashMap::new(),
+            globals: HashMap::new(),
+            stubs: ptr::null_mut(),
+            stub_count: 0,
+            stub_for: HashMap::new(),
+            dwarf: BTreeMap::new(),
+            debug_sections: HashMap::new(),
+            eh_frames: Vec::new(),
+        };
+
+        let blocks = loader.layout()?;
+        loader.collect_globals()?;
+        loader.relocate(resolve)?;
+
+        for &(block, start, len) in blocks.iter() {
+            let prot = match block {
+                Block::Text => libc::PROT_READ | libc::PROT_EXEC,
+                Block::ReadOnly => libc::PROT_READ,
+                Block::Data => libc::PROT_READ | libc::PROT_WRITE,
+            };
+            if len > 0 {
+                clear_instruction_cache(start, len);
+                near::protect(start, len, prot)?;
+            }
+        }
+
+        let text = blocks
+            .iter()
+            .find(|(block, ..)| *block == Block::Text)
+            .map(|(_, start, len)| (*start as usize, *len))
+            .unwrap_or_default();
+        if let Err(err) = jit_debug::register(&loader.debug_info(text)?) {
+            eprintln!("couldn't register the patch with debuggers: {err}");
+        }
+
+        // The unwinder keeps pointing into the frames, which is fine since patches are never freed
+        for &eh_frame in loader.eh_frames.iter() {
+            unsafe { __register_frame(eh_frame as *const u8) };
+        }
+
+        loader.run_initializers()?;
+
+        Ok(Self {
+            symbols: loader.globals,
+        })
+    }
+
+    /// The runtime address of a symbol the objects define
+    pub fn get(&self, name: &str) -> Option<*const ()> {
+        self.symbols.get(name).map(|addr| *addr as *const ())
+    }
+}
+
+impl<'data> Loader<'data> {
+    /// Give every allocated section and common symbol an address and copy the section contents in
+    fn layout(&mut self) -> anyhow::Result<Vec<(Block, *mut u8, usize)>> {
+        let mut placed = Vec::new();
+        let mut sizes = [
+            (Block::Text, 0usize, 1usize),
+            (Block::ReadOnly, 0, 1),
+            (Block::Data, 0, 1),
+        ];
+        let mut commons = Vec::new();
+        let mut debug = Vec::new();
+        let mut symbol_count = 0;
+
+        for (index, file) in self.files.iter().enumerate() {
+            for section in file.sections() {
+                let name = section.name()?;
+                if name.starts_with(".debug_") {
+                    let buffer = self.dwarf.entry(name.to_string()).or_default();
+                    debug.push((index, section.index(), name.to_string(), buffer.len()));
+                    buffer.extend_from_slice(&section.uncompressed_data()?);
+                    continue;
+                }
+
+                let Some(block) = block_of(&section)? else {
+                    continue;
+                };
+                // The unwinder reads an `.eh_frame` up to a zero length, which the linker would
+                // normally add at the end of the merged section
+                let terminator = match name == ".eh_frame" {
+                    true => 4,
+                    false => 0,
+                };
+                let (_, size, align) = sizes.iter_mut().find(|(b, ..)| *b == block).unwrap();
+                let offset = size.next_multiple_of(section.align().max(1) as usize);
+                *size = offset + section.size() as usize + terminator;
+                *align = (*align).max(section.align() as usize);
+                placed.push((index, section.index(), block, offset));
+            }
+
+            for symbol in file.symbols() {
+                symbol_count += 1;
+                if symbol.is_common() {
+                    // A common symbol's value is its alignment
+                    let (_, size, _) = &mut sizes[2];
+                    let offset = size.next_multiple_of(symbol.address().max(1) as usize);
+                    *size = offset + symbol.size() as usize;
+                    commons.push((index, symbol.index(), offset));
+                }
+            }
+        }
+
+        // The stubs live right behind the code so calls can always reach them
+        let stub_offset = sizes[0].1.next_multiple_of(std::mem::align_of::<Stub>());
+        sizes[0].1 = stub_offset + symbol_count * std::mem::size_of::<Stub>();
+
+        let mut blocks = Vec::new();
+        for (block, size, align) in sizes {
+            let start = match size {
+                0 => ptr::null_mut(),
+                _ => near::alloc(size, align)?,
+            };
+            blocks.push((block, start, size));
+        }
+        let start_of = |block: Block| blocks.iter().find(|(b, ..)| *b == block).unwrap().1 as usize;
+
+        for (index, section_index, block, offset) in placed {
+            let addr = start_of(block) + offset;
+            let section = self.files[index].section_by_index(section_index)?;
+            if section.kind() != object::SectionKind::UninitializedData {
+                let bytes = section.data()?;
+                unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len()) };
+            }
+            if section.name()? == ".eh_frame" {
+                write::<u32>(addr + section.size() as usize, 0);
+                self.eh_frames.push(addr);
+            }
+            self.sections.insert((index, section_index), addr);
+        }
+
+        for (index, symbol_index, offset) in commons {
+            self.commons
+                .insert((index, symbol_index), start_of(Block::Data) + offset);
+        }
+
+        // The buffers are done growing, so their bytes stay put from here on
+        for (index, section_index, name, offset) in debug {
+            let buffer = self.dwarf.get_mut(&name).unwrap();
+            self.debug_sections.insert(
+                (index, section_index),
+                buffer.as_mut_ptr() as usize + offset,
+            );
+            self.sections.insert((index, section_index), offset);
+        }
+
+        self.stubs = (start_of(Block::Text) + stub_offset) as *mut Stub;
+        Ok(blocks)
+    }
+
+    fn collect_globals(&mut self) -> anyhow::Result<()> {
+        let mut globals = HashMap::new();
+        for (index, file) in self.files.iter().enumerate() {
+            for symbol in file.symbols() {
+                if !symbol.is_global() || symbol.is_undefined() {
+                    continue;
+                }
+                let Some(addr) = self.defined_address(index, &symbol)? else {
+                    continue;
+                };
+
+                // A strong definition beats a weak one, otherwise the first one wins
+                let name = symbol.name()?.to_string();
+                if !symbol.is_weak() || !globals.contains_key(&name) {
+                    globals.insert(name, addr);
+                }
+            }
+        }
+
+        self.globals = globals;
+        Ok(())
+    }
+
+    /// Where a symbol the objects define ended up
+    fn defined_address(
+        &self,
+        index: usize,
+        symbol: &object::read::elf::ElfSymbol64<'data, '_, Endianness>,
+    ) -> anyhow::Result<Option<usize>> {
+        Ok(match symbol.section() {
+            SymbolSection::Section(section) => self
+                .sections
+                .get(&(index, section))
+                .map(|base| base + symbol.address() as usize),
+            SymbolSection::Absolute => Some(symbol.address() as usize),
+            SymbolSection::Common => self.commons.get(&(index, symbol.index())).copied(),
+            _ => None,
+        })
+    }
+
+    fn relocate(&mut self, resolve: &dyn Fn(&str) -> Option<usize>) -> anyhow::Result<()> {
+        let mut fixups = Vec::new();
+        let mut missing = Vec::new();
+
+        for (index, file) in self.files.iter().enumerate() {
+            for section in file.sections() {
+                let key = (index, section.index());
+                let debug = self.debug_sections.get(&key).copied();
+                let Some(base) = debug.or_else(|| self.sections.get(&key).copied()) else {
+                    continue;
+                };
+
+                for (offset, reloc) in section.relocations() {
+                    let place = base + offset as usize;
+                    let RelocationFlags::Elf { r_type } = reloc.flags() else {
+                        continue;
+                    };
+                    let RelocationTarget::Symbol(symbol_index) = reloc.target() else {
+                        anyhow::bail!("relocation at {place:#x} has no symbol");
+                    };
+
+                    // Locals bind within their object, globals to the winning definition across
+                    // the patch and then to the app
+                    let symbol = file.symbol_by_index(symbol_index)?;
+                    let name = symbol.name()?;
+                    let local = match symbol.is_global() {
+                        true => None,
+                        false => self.defined_address(index, &symbol)?,
+                    };
+                    let target = local
+                        .or_else(|| self.globals.get(name).copied())
+                        .or_else(|| resolve(name));
+
+                    // Debug info pointing at something we can't find just ends up a little wrong
+                    let fixup = |target| (r_type, place, target, reloc.addend(), debug.is_some());
+                    match target {
+                        Some(target) => fixups.push(fixup(target)),
+                        None if symbol.is_weak() || debug.is_some() => fixups.push(fixup(0)),
+                        None => missing.push(name.to_string()),
+                    }
+                }
+            }
+        }
+
+        missing.sort();
+        missing.dedup();
+        anyhow::ensure!(
+            missing.is_empty(),
+            "the app doesn't have {}",
+            missing.join(", ")
+        );
+
+        for (r_type, place, target, addend, debug) in fixups {
+            match self.apply(r_type, place, target, addend) {
+                Err(_) if debug => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The functions the objects define and their relocated DWARF, for a debugger
+    fn debug_info(&mut self, text: (usize, usize)) -> anyhow::Result<jit_debug::DebugInfo> {
+        let mut functions = Vec::new();
+        for (index, file) in self.files.iter().enumerate() {
+            for symbol in file.symbols() {
+                if symbol.kind() != object::SymbolKind::Text || symbol.size() == 0 {
+                    continue;
+                }
+                if let Some(addr) = self.defined_address(index, &symbol)? {
+                    functions.push((symbol.name()?.to_string(), addr, symbol.size() as usize));
+                }
+            }
+        }
+
+        Ok(jit_debug::DebugInfo {
+            text,
+            functions,
+            dwarf: std::mem::take(&mut self.dwarf).into_iter().collect(),
+        })
+    }
+
+    /// The stub for a target, made on first use
+    fn stub(&mut self, target: usize) -> usize {
+        if let Some(stub) = self.stub_for.get(&target) {
+            return *stub as usize;
+        }
+
+        let stub = unsafe { self.stubs.add(self.stub_count) };
+        unsafe { stub.write(Stub::new(self.arch, target)) };
+        self.stub_count += 1;
+        self.stub_for.insert(target, stub);
+        stub as usize
+    }
+
+    fn apply(
+        &mut self,
+        r_type: u32,
+        place: usize,
+        target: usize,
+        addend: i64,
+    ) -> anyhow::Result<()> {
+        let s = target as i64;
+        let p = place as i64;
+
+        match (self.arch, r_type) {
+            (Architecture::X86_64, elf::R_X86_64_64) => write::<u64>(place, (s + addend) as u64),
+            (Architecture::X86_64, elf::R_X86_64_PC64) => write::<i64>(place, s + addend - p),
+            // Only usable if the patch lands in the low 4GB, which it won't next to a PIE
+            (Architecture::X86_64, elf::R_X86_64_32) => write::<u32>(
+                place,
+                u32::try_from(s + addend).map_err(|_| not_pic(place))?,
+            ),
+            (Architecture::X86_64, elf::R_X86_64_32S) => write::<i32>(
+                place,
+                i32::try_from(s + addend).map_err(|_| not_pic(place))?,
+            ),
+            (Architecture::X86_64, elf::R_X86_64_PC32) => {
+                write::<i32>(place, in_range(s + addend - p, place)?)
+            }
+            (Architecture::X86_64, elf::R_X86_64_PLT32) => {
+                let value = match i32::try_from(s + addend - p) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        let trampoline = self.stub(target) + std::mem::offset_of!(Stub, trampoline);
+                        in_range(trampoline as i64 + addend - p, place)?
+                    }
+                };
+                write::<i32>(place, value)
+            }
+            (
+                Architecture::X86_64,
+                elf::R_X86_64_GOTPCREL | elf::R_X86_64_GOTPCRELX | elf::R_X86_64_REX_GOTPCRELX,
+            ) => {
+                let entry = self.stub(target) as i64;
+                write::<i32>(place, in_range(entry + addend - p, place)?)
+            }
+
+            (Architecture::Aarch64, elf::R_AARCH64_ABS64) => {
+                write::<u64>(place, (s + addend) as u64)
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_ABS32) => write::<u32>(
+                place,
+                u32::try_from(s + addend).map_err(|_| not_pic(place))?,
+            ),
+            (Architecture::Aarch64, elf::R_AARCH64_PREL64) => write::<i64>(place, s + addend - p),
+            (Architecture::Aarch64, elf::R_AARCH64_PREL32) => {
+                write::<i32>(place, in_range(s + addend - p, place)?)
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_CALL26 | elf::R_AARCH64_JUMP26) => {
+                let mut offset = s + addend - p;
+                if !(-(1 << 27)..1 << 27).contains(&offset) {
+                    let trampoline = self.stub(target) + std::mem::offset_of!(Stub, trampoline);
+                    offset = trampoline as i64 - p;
+                }
+                patch_insn(place, 0x03ff_ffff, 0, (offset >> 2) as u32)
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_ADR_PREL_PG_HI21) => {
+                adrp(place, (s + addend) as usize)?
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_ADR_GOT_PAGE) => {
+                let entry = self.stub(target);
+                adrp(place, entry)?
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_LD64_GOT_LO12_NC) => {
+                let entry = self.stub(target);
+                patch_insn(place, 0xfff, 10, (entry as u32 & 0xfff) >> 3)
+            }
+            (Architecture::Aarch64, elf::R_AARCH64_ADD_ABS_LO12_NC) => {
+                patch_insn(place, 0xfff, 10, (s + addend) as u32 & 0xfff)
+            }
+            (
+                Architecture::Aarch64,
+                kind @ (elf::R_AARCH64_LDST8_ABS_LO12_NC
+                | elf::R_AARCH64_LDST16_ABS_LO12_NC
+                | elf::R_AARCH64_LDST32_ABS_LO12_NC
+                | elf::R_AARCH64_LDST64_ABS_LO12_NC
+                | elf::R_AARCH64_LDST128_ABS_LO12_NC),
+            ) => {
+                let shift = match kind {
+                    elf::R_AARCH64_LDST8_ABS_LO12_NC => 0,
+                    elf::R_AARCH64_LDST16_ABS_LO12_NC => 1,
+                    elf::R_AARCH64_LDST32_ABS_LO12_NC => 2,
+                    elf::R_AARCH64_LDST64_ABS_LO12_NC => 3,
+                    _ => 4,
+                };
+                patch_insn(place, 0xfff, 10, ((s + addend) as u32 & 0xfff) >> shift)
+            }
+
+            (_, other) => anyhow::bail!(
+                "can't apply relocation type {other} at {place:#x} - thread-locals have to be \
+                 stripped from the patch before it's loaded"
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Run each object's `.init_array`, now that the pointers in it are relocated
+    fn run_initializers(&self) -> anyhow::Result<()> {
+        for (index, file) in self.files.iter().enumerate() {
+            for section in file.sections() {
+                if section.elf_section_header().sh_type(file.endian()) != elf::SHT_INIT_ARRAY {
+                    continue;
+                }
+                let Some(&base) = self.sections.get(&(index, section.index())) else {
+                    continue;
+                };
+
+                let inits = base as *const extern "C" fn();
+                for i in 0..section.size() as usize / std::mem::size_of::<usize>() {
+                    unsafe { (*inits.add(i))() };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which block a section is loaded into, if it's loaded at all
+fn block_of(section: &ElfSection64<'_, '_, Endianness>) -> anyhow::Result<Option<Block>> {
+    let header = section.elf_section_header();
+    let flags = header.sh_flags(section.elf_file().endian());
+
+    if flags & u64::from(elf::SHF_ALLOC) == 0 || section.size() == 0 {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        flags & u64::from(elf::SHF_TLS) == 0,
+        "{} holds thread-locals, which have to be stripped from the patch before it's loaded",
+        section.name()?
+    );
+
+    Ok(Some(if flags & u64::from(elf::SHF_EXECINSTR) != 0 {
+        Block::Text
+    } else if flags & u64::from(elf::SHF_WRITE) != 0 {
+        Block::Data
+    } else {
+        Block::ReadOnly
+    }))
+}
+
+fn in_range(value: i64, place: usize) -> anyhow::Result<i32> {
+    i32::try_from(value).map_err(|_| {
+        anyhow::anyhow!("relocation at {place:#x} can't reach its target ({value:#x} away)")
+    })
+}
+
+fn not_pic(place: usize) -> anyhow::Error {
+    anyhow::anyhow!("absolute 32-bit relocation at {place:#x} - the patch has to be built as PIC")
+}
+
+fn write<T>(place: usize, value: T) {
+    unsafe { (place as *mut T).write_unaligned(value) }
+}
+
+/// Replace the bits under `mask << shift` in the instruction at `place`
+fn patch_insn(place: usize, mask: u32, shift: u32, value: u32) {
+    let insn = unsafe { (place as *const u32).read_unaligned() };
+    write::<u32>(place, (insn & !(mask << shift)) | ((value & mask) << shift));
+}
+
+/// Point an `ADRP` at the page holding `target`
+fn adrp(place: usize, target: usize) -> anyhow::Result<()> {
+    let pages = ((target & !0xfff) as i64 - (place & !0xfff) as i64) >> 12;
+    anyhow::ensure!(
+        (-(1 << 20)..1 << 20).contains(&pages),
+        "ADRP at {place:#x} can't reach {target:#x}"
+    );
+
+    let pages = pages as u32;
+    patch_insn(place, 0x3, 29, pages & 0x3);
+    patch_insn(place, 0x7ffff, 5, (pages >> 2) & 0x7ffff);
+    Ok(())
+}
+
+#[cfg(target_arch = "aarch64")]
+fn clear_instruction_cache(start: *mut u8, len: usize) {
+    extern "C" {
+        fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+    }
+    unsafe { __clear_cache(start.cast(), start.add(len).cast()) };
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn clear_instruction_cache(_start: *mut u8, _len: usize) {}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+pub(crate) mod tests {
+    use super::*;
+    use object::{
+        write::{
+            Object as WriteObject, Relocation, SectionId, StandardSection, Symbol, SymbolId,
+            SymbolSection,
+        },
+        BinaryFormat, SectionFlags, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+    };
+
+    fn elf() -> WriteObject<'static> {
+        WriteObject::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little)
+    }
+
+    fn relocate(
+        obj: &mut WriteObject,
+        section: SectionId,
+        offset: u64,
+        symbol: SymbolId,
+        addend: i64,
+        r_type: u32,
+    ) {
+        let flags = RelocationFlags::Elf { r_type };
+        obj.add_relocation(
+            section,
+            Relocation {
+                offset,
+                symbol,
+                addend,
+                flags,
+            },
+        )
+        .unwrap();
+    }
+
+    /// Write an object where the loader can read it, named after the test
+    pub(crate) fn write_object(obj: &WriteObject, name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.o", std::process::id()));
+        std::fs::write(&path, obj.write().unwrap()).unwrap();
+        path
+    }
+
+    fn symbol(name: &str, section: SymbolSection, value: u64) -> Symbol {
+        Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size: 0,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Dynamic,
+            weak: false,
+            section,
+            flags: SymbolFlags::None,
+        }
+    }
+
+    /// An object whose `answer` returns 43: a counter starting at 41, bumped by an initializer,
+    /// plus `abs(-1)` from outside the patch
+    pub(crate) fn answer_object() -> WriteObject<'static> {
+        let mut obj = elf();
+
+        #[rustfmt::skip]
+        let code = [
+            // answer: counter + abs(-1)
+            0x48, 0x8b, 0x05, 0, 0, 0, 0, // mov rax, [rip + counter@GOTPCREL]
+            0x8b, 0x00,                   // mov eax, [rax]
+            0x48, 0x83, 0xec, 0x08,       // sub rsp, 8
+            0x89, 0x04, 0x24,             // mov [rsp], eax
+            0xbf, 0xff, 0xff, 0xff, 0xff, // mov edi, -1
+            0xe8, 0, 0, 0, 0,             // call abs@PLT
+            0x03, 0x04, 0x24,             // add eax, [rsp]
+            0x48, 0x83, 0xc4, 0x08,       // add rsp, 8
+            0xc3,                         // ret
+            // init: counter += 1
+            0x48, 0x8b, 0x05, 0, 0, 0, 0, // mov rax, [rip + counter@GOTPCREL]
+            0x83, 0x00, 0x01,             // add dword [rax], 1
+            0xc3,                         // ret
+        ];
+        let text = obj.section_id(StandardSection::Text);
+        obj.append_section_data(text, &code, 16);
+        let data = obj.section_id(StandardSection::Data);
+        obj.append_section_data(data, &41u32.to_le_bytes(), 4);
+
+        obj.add_symbol(symbol("answer", SymbolSection::Section(text), 0));
+        let init = obj.add_symbol(symbol("init", SymbolSection::Section(text), 34));
+        let counter = obj.add_symbol(symbol("counter", SymbolSection::Section(data), 0));
+        let abs = obj.add_symbol(symbol("abs", SymbolSection::Undefined, 0));
+
+        for (offset, r_type, target) in [
+            (3, elf::R_X86_64_REX_GOTPCRELX, counter),
+            (22, elf::R_X86_64_PLT32, abs),
+            (37, elf::R_X86_64_REX_GOTPCRELX, counter),
+        ] {
+            relocate(&mut obj, text, offset, target, -4, r_type);
+        }
+
+        let init_array = obj.add_section(
+            vec![],
+            b".init_array".to_vec(),
+            SectionKind::Elf(elf::SHT_INIT_ARRAY),
+        );
+        obj.section_mut(init_array).flags = SectionFlags::Elf {
+            sh_flags: u64::from(elf::SHF_ALLOC | elf::SHF_WRITE),
+        };
+        obj.append_section_data(init_array, &[0; 8], 8);
+        relocate(&mut obj, init_array, 0, init, 0, elf::R_X86_64_64);
+
+        obj
+    }
+
+    #[test]
+    fn loads_and_relocates_objects() {
+        let obj = answer_object();
+        let path = write_object(&obj, "patch-objects");
+
+        // The region was reserved by the initializer in lib.rs before the tests started
+        let abs = libc::abs as unsafe extern "C" fn(i32) -> i32;
+        let resolve = |name: &str| (name == "abs").then_some(abs as usize);
+        let image = ObjectImage::load(std::slice::from_ref(&path), &resolve).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let answer: extern "C" fn() -> i32 =
+            unsafe { std::mem::transmute(image.get("answer").unwrap()) };
+        assert_eq!(answer(), 43);
+
+        let missing = |_: &str| None;
+        let path = write_object(&obj, "patch-missing");
+        let err = ObjectImage::load(std::slice::from_ref(&path), &missing)
+            .err()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("abs"), "{err}");
+    }
+
+    #[test]
+    fn registers_unwind_info() {
+        let mut obj = elf();
+        let text = obj.section_id(StandardSection::Text);
+        obj.append_section_data(text, &[0x90, 0xc3], 16);
+        let section = obj.add_symbol(symbol(".text", SymbolSection::Section(text), 0));
+        obj.add_symbol(symbol("unwinds", SymbolSection::Section(text), 0));
+
+        #[rustfmt::skip]
+        let eh_frame = [
+            // CIE: "zR", pc-relative sdata4 pointers, CFA = rsp + 8, return address at CFA - 8
+            20, 0, 0, 0,
+            0, 0, 0, 0,
+            1, b'z', b'R', 0,
+            1, 0x78, 16, 1, 0x1b,
+            0x0c, 0x07, 0x08, 0x90, 0x01,
+            0, 0,
+            // FDE covering both bytes of `unwinds`
+            16, 0, 0, 0,
+            28, 0, 0, 0,
+            0, 0, 0, 0,
+            2, 0, 0, 0,
+            0, 0, 0, 0,
+        ];
+        let frames = obj.add_section(vec![], b".eh_frame".to_vec(), SectionKind::ReadOnlyData);
+        obj.append_section_data(frames, &eh_frame, 8);
+        relocate(&mut obj, frames, 32, section, 0, elf::R_X86_64_PC32);
+
+        let path = write_object(&obj, "patch-unwind");
+        let image = ObjectImage::load(std::slice::from_ref(&path), &|_| None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        #[repr(C)]
+        struct Bases {
+            text: usize,
+            data: usize,
+            func: usize,
+        }
+        extern "C" {
+            fn _Unwind_Find_FDE(pc: *const u8, bases: *mut Bases) -> *const u8;
+        }
+
+        let unwinds = image.get("unwinds").unwrap() as usize;
+        let mut bases = Bases {
+            text: 0,
+            data: 0,
+            func: 0,
+        };
+        let fde = unsafe { _Unwind_Find_FDE((unwinds + 1) as *const u8, &mut bases) };
+        assert!(!fde.is_null());
+        assert_eq!(bases.func, unwinds);
+    }
+}
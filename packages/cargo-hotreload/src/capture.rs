@@ -0,0 +1,179 @@
+//! Capture the exact rustc invocations cargo makes by sitting in front of rustc as the
+//! `RUSTC_WORKSPACE_WRAPPER`.
+//!
+//! Cargo calls us with the path to rustc followed by its args. We write the argv, environment and
+//! working directory out to disk - one file per crate and target kind - and then run rustc as normal.
+//! The driver reads these back and replays the app's invocation for fast rebuilds.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf};
+use tokio::process::Command;
+
+/// Set on the cargo process to point the wrapper at the directory captures are written to
+pub const CAPTURE_DIR_ENV: &str = "HOTRELOAD_RUSTC_CAPTURE";
+
+/// A crate is identified by its name and the kind of target being built since a package can have
+/// a lib, a bin and a build script that all share a name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CrateKey {
+    pub name: String,
+    pub kind: String,
+}
+
+impl CrateKey {
+    pub fn new(name: &str, kind: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+        }
+    }
+
+    /// Pull the crate name and crate type out of a rustc invocation
+    fn from_invocation(args: &[String], envs: &BTreeMap<String, String>) -> Option<Self> {
+        let name = arg_value(args, "--crate-name")?;
+
+        // Build scripts are compiled as bins and are all called `build_script_build`, so we key
+        // them by their package instead
+        if name.starts_with("build_script_") {
+            let package = envs.get("CARGO_PKG_NAME").map(|s| s.as_str())?;
+            return Some(Self::new(package, "custom-build"));
+        }
+
+        let kind = arg_value(args, "--crate-type").unwrap_or("lib");
+        Some(Self::new(name, kind))
+    }
+}
+
+impl Display for CrateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.name, self.kind)
+    }
+}
+
+/// A single rustc invocation, exactly as cargo made it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedRustc {
+    pub rustc: PathBuf,
+    pub args: Vec<String>,
+    pub envs: BTreeMap<String, String>,
+    pub cwd: PathBuf,
+}
+
+impl CapturedRustc {
+    pub fn key(&self) -> Option<CrateKey> {
+        CrateKey::from_invocation(&self.args, &self.envs)
+    }
+
+    /// A command that replays this invocation
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.rustc);
+        cmd.args(&self.args)
+            .env_clear()
+            .envs(&self.envs)
+            .current_dir(&self.cwd);
+        cmd
+    }
+
+    /// The `--extern` args of the invocation. The rlib names carry a hash of the dependency's
+    /// features and version, so if these change then the dependencies themselves changed.
+    pub fn extern_args(&self) -> Vec<&str> {
+        self.args
+            .windows(2)
+            .filter(|w| w[0] == "--extern")
+            .map(|w| w[1].as_str())
+            .collect()
+    }
+}
+
+/// Run as the workspace wrapper: record the invocation and then hand off to rustc
+pub async fn wrap_rustc(capture_dir: PathBuf) -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let rustc: PathBuf = args.next().context("No rustc passed to wrapper")?.into();
+    let args = args.collect::<Vec<_>>();
+
+    let captured = CapturedRustc {
+        rustc: rustc.clone(),
+        args: args.clone(),
+        envs: std::env::vars()
+            .filter(|(k, _)| k != CAPTURE_DIR_ENV)
+            .collect(),
+        cwd: std::env::current_dir()?,
+    };
+
+    // Cargo probes rustc with things like `-vV` - only record real compilations
+    if let Some(key) = captured.key() {
+        std::fs::create_dir_all(&capture_dir)?;
+        std::fs::write(
+            capture_dir.join(format!("{key}.json")),
+            serde_json::to_string_pretty(&captured)?,
+        )?;
+    }
+
+    // Stdio is inherited - cargo is parsing rustc's json output on the other end
+    let status = Command::new(rustc)
+        .args(args)
+        .env_remove(CAPTURE_DIR_ENV)
+        .status()
+        .await?;
+
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Load every invocation the wrapper has captured so far
+pub fn load_captures(capture_dir: &std::path::Path) -> Result<BTreeMap<CrateKey, CapturedRustc>> {
+    let mut captures = BTreeMap::new();
+
+    for entry in std::fs::read_dir(capture_dir)?.flatten() {
+        let captured: CapturedRustc =
+            serde_json::from_str(&std::fs::read_to_string(entry.path())?)?;
+
+        if let Some(key) = captured.key() {
+            captures.insert(key, captured);
+        }
+    }
+
+    Ok(captures)
+}
+
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|idx| args.get(idx + 1))
+        .map(|s| s.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    fn key(args: &str, package: &str) -> Option<CrateKey> {
+        let args = args.split_whitespace().map(|s| s.to_string()).collect_vec();
+        let envs = BTreeMap::from([("CARGO_PKG_NAME".to_string(), package.to_string())]);
+        CrateKey::from_invocation(&args, &envs)
+    }
+
+    #[test]
+    fn keys_by_crate_name_and_kind() {
+        assert_eq!(
+            key(
+                "--crate-name harness src/main.rs --crate-type bin",
+                "harness"
+            ),
+            Some(CrateKey::new("harness", "bin"))
+        );
+        assert_eq!(
+            key(
+                "--crate-name build_script_build build.rs --crate-type bin",
+                "harness"
+            ),
+            Some(CrateKey::new("harness", "custom-build"))
+        );
+        assert_eq!(
+            key("--crate-name binary_patch src/lib.rs", "binary-patch"),
+            Some(CrateKey::new("binary_patch", "lib"))
+        );
+        assert_eq!(key("-vV", "harness"), None);
+    }
+}
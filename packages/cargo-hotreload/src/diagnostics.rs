@@ -0,0 +1,457 @@
+//! Linker errors turned into something we can act on
+//!
+//! GNU ld, lld and ld64 all word the same problems differently. We pull out the handful that hot
+//! patching actually runs into, demangle the symbols, and attach a hint about which limitation of
+//! patching was hit. Anything we don't recognize is kept as-is so nothing gets lost.
+
+use crate::symbol::demangle;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkDiagnostic {
+    pub severity: Severity,
+    pub problem: LinkProblem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+
+    /// Anything the linker printed that it didn't call an error or a warning
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LinkProblem {
+    UndefinedSymbol {
+        symbol: String,
+        referenced_from: Vec<String>,
+    },
+    DuplicateSymbol {
+        symbol: String,
+        defined_in: Vec<String>,
+    },
+    RelocationOutOfRange {
+        symbol: Option<String>,
+        message: String,
+    },
+
+    /// An ADRP (or `R_AARCH64_ADR_PREL_PG_HI21`) that can't reach its target's page
+    PageViolation {
+        from: Option<String>,
+        to: Option<String>,
+        message: String,
+    },
+    Other {
+        message: String,
+    },
+}
+
+impl LinkDiagnostic {
+    fn error(problem: LinkProblem) -> Self {
+        Self {
+            severity: Severity::Error,
+            problem,
+        }
+    }
+
+    /// Which limitation of hot patching this points at
+    pub fn hint(&self) -> Option<&'static str> {
+        match self.problem {
+            LinkProblem::UndefinedSymbol { .. } => Some(
+                "the patch needs a symbol the running app doesn't export - it was probably \
+                 inlined or dead-stripped from the original build, so a restart is needed",
+            ),
+            LinkProblem::DuplicateSymbol { .. } => Some(
+                "the symbol is defined both in the patch and in the stub that points back into \
+                 the running app - an unchanged symbol got pulled into the patch",
+            ),
+            LinkProblem::RelocationOutOfRange { .. } => Some(
+                "the patch got loaded too far from the app for a direct call - branches only \
+                 reach ±128MB, so it has to be loaded near the original binary",
+            ),
+            LinkProblem::PageViolation { .. } => Some(
+                "ADRP assumes its target is in the same image - references from the patch into \
+                 the app's data have to go through the GOT instead",
+            ),
+            LinkProblem::Other { .. } => None,
+        }
+    }
+}
+
+impl Display for LinkDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |items: &[String]| items.join(", ");
+
+        match &self.problem {
+            LinkProblem::UndefinedSymbol {
+                symbol,
+                referenced_from,
+            } => match referenced_from.is_empty() {
+                true => write!(f, "undefined symbol `{symbol}`")?,
+                false => write!(
+                    f,
+                    "undefined symbol `{symbol}`, referenced from {}",
+                    list(referenced_from)
+                )?,
+            },
+            LinkProblem::DuplicateSymbol { symbol, defined_in } => match defined_in.is_empty() {
+                true => write!(f, "duplicate symbol `{symbol}`")?,
+                false => write!(f, "duplicate symbol `{symbol}` in {}", list(defined_in))?,
+            },
+            LinkProblem::RelocationOutOfRange { message, .. }
+            | LinkProblem::PageViolation { message, .. }
+            | LinkProblem::Other { message } => write!(f, "{message}")?,
+        }
+
+        if let Some(hint) = self.hint() {
+            write!(f, "\n    hint: {hint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the stderr of a link. Diagnostics come out in the order the linker printed them.
+pub fn parse_link_errors(stderr: &str) -> Vec<LinkDiagnostic> {
+    let mut diagnostics = vec![];
+
+    // GNU ld names the function on the line before the error, if there is one
+    let mut function_context = None;
+
+    // Both ld64 and lld list the details of an error on the lines that follow it
+    let mut lines = stderr.lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line.trim_end();
+        let body = strip_linker_prefix(line);
+        let function = function_context.take();
+
+        // ld64: `Undefined symbols for architecture arm64:` then `  "_foo", referenced from:`
+        if line.starts_with("Undefined symbols for architecture") {
+            while let Some(next) = lines.next_if(|l| l.starts_with(' ')) {
+                let next = next.trim();
+                if let Some(symbol) = quoted(next, '"').filter(|_| next.ends_with("from:")) {
+                    diagnostics.push(LinkDiagnostic::error(LinkProblem::UndefinedSymbol {
+                        symbol: demangle(symbol),
+                        referenced_from: vec![],
+                    }));
+                    continue;
+                }
+
+                let referrer = next.split(" in ").next().unwrap_or(next);
+                if let Some(LinkDiagnostic {
+                    problem:
+                        LinkProblem::UndefinedSymbol {
+                            referenced_from, ..
+                        },
+                    ..
+                }) = diagnostics.last_mut()
+                {
+                    referenced_from.push(demangle(referrer));
+                }
+            }
+            continue;
+        }
+
+        // ld64: `duplicate symbol '_foo' in:` then the objects, one per line
+        if let Some(rest) = body.strip_prefix("duplicate symbol ") {
+            let symbol = quoted(rest, '\'').unwrap_or_else(|| rest.trim_end_matches(" in:"));
+            let mut defined_in = vec![];
+            while let Some(next) = lines.next_if(|l| l.starts_with(' ')) {
+                defined_in.push(next.trim().to_string());
+            }
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::DuplicateSymbol {
+                symbol: demangle(symbol),
+                defined_in,
+            }));
+            continue;
+        }
+
+        // lld: `error: undefined symbol: foo` then `>>> referenced by main.rs` lines
+        if let Some(symbol) = body.strip_prefix("error: undefined symbol: ") {
+            let referenced_from = details(&mut lines, "referenced by ");
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::UndefinedSymbol {
+                symbol: demangle(symbol.trim()),
+                referenced_from,
+            }));
+            continue;
+        }
+
+        // lld: `error: duplicate symbol: foo` then `>>> defined at a.rs` lines
+        if let Some(symbol) = body.strip_prefix("error: duplicate symbol: ") {
+            let defined_in = details(&mut lines, "defined at ");
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::DuplicateSymbol {
+                symbol: demangle(symbol.trim()),
+                defined_in,
+            }));
+            continue;
+        }
+
+        if let Some(function) = line
+            .split_once(": in function ")
+            .and_then(|(_, f)| quoted(f, '`'))
+        {
+            function_context = Some(demangle(function));
+            continue;
+        }
+
+        // GNU ld: `a.o: in function `bar':` then `(.text+0x5): undefined reference to `foo'`
+        if let Some((_, symbol)) = line.split_once("undefined reference to `") {
+            let symbol = symbol.trim_end_matches('\'');
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::UndefinedSymbol {
+                symbol: demangle(symbol),
+                referenced_from: function.into_iter().collect(),
+            }));
+            continue;
+        }
+
+        // GNU ld: `(.text+0x0): multiple definition of `foo'; b.o:(.text+0x0): first defined here`
+        if let Some((location, rest)) = line.split_once(": multiple definition of `") {
+            let (symbol, first) = rest.split_once('\'').unwrap_or((rest, ""));
+            let first = first.trim_start_matches(';').trim();
+            let first = first.trim_end_matches(": first defined here");
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::DuplicateSymbol {
+                symbol: demangle(symbol),
+                defined_in: [location, first]
+                    .into_iter()
+                    .filter(|l| !l.is_empty())
+                    .map(|l| strip_linker_prefix(l).to_string())
+                    .collect(),
+            }));
+            continue;
+        }
+
+        let message = crate::symbol::demangle_text(body);
+
+        if line.contains("ADRP") || line.contains("ADR_PREL_PG") {
+            let mut names = quoted_all(line, '\'').into_iter().map(demangle);
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::PageViolation {
+                from: names.next(),
+                to: names.next(),
+                message,
+            }));
+            continue;
+        }
+
+        if line.contains("out of range") || line.contains("relocation truncated to fit") {
+            // lld ends with `; references foo`, GNU ld with `against symbol `foo'`, ld64 names the
+            // target after `to`
+            let symbol = line
+                .rsplit_once("; references ")
+                .map(|(_, s)| s.trim())
+                .or_else(|| quoted(line.split_once("against symbol ")?.1, '`'))
+                .or_else(|| line.split_once(" to ")?.1.split_whitespace().next())
+                .map(demangle);
+            let details = details(&mut lines, "");
+            let message = match details.is_empty() {
+                true => message,
+                false => format!("{message} ({})", details.join(", ")),
+            };
+            diagnostics.push(LinkDiagnostic::error(LinkProblem::RelocationOutOfRange {
+                symbol,
+                message,
+            }));
+            continue;
+        }
+
+        // Summary lines that repeat what we already have
+        if body.starts_with("symbol(s) not found")
+            || body.ends_with("duplicate symbols")
+            || body.starts_with("collect2:")
+            || body.starts_with("clang: error: linker command failed")
+            || body.is_empty()
+        {
+            continue;
+        }
+
+        diagnostics.push(LinkDiagnostic {
+            severity: rated_severity(body),
+            problem: LinkProblem::Other { message },
+        });
+    }
+
+    diagnostics
+}
+
+/// How the tool that printed a line we don't recognize rated it, going by an `error:` or `warning:`
+/// at the start or right after the tool's name, like `clang: warning: ...`. Only an explicit
+/// `error:` fails the link - the rest is informational.
+fn rated_severity(body: &str) -> Severity {
+    let rated = |label: &str| {
+        body.starts_with(label)
+            || body.split_once(": ").is_some_and(|(tool, rest)| {
+                !tool.contains(char::is_whitespace) && rest.starts_with(label)
+            })
+    };
+
+    if rated("error:") {
+        Severity::Error
+    } else if rated("warning:") {
+        Severity::Warning
+    } else {
+        Severity::Note
+    }
+}
+
+/// `ld: `, `ld.lld: `, `/usr/bin/ld: ` and friends
+fn strip_linker_prefix(line: &str) -> &str {
+    for prefix in ["ld.lld: ", "ld64.lld: ", "ld: "] {
+        if let Some((_, rest)) = line.split_once(prefix) {
+            return rest;
+        }
+    }
+    line
+}
+
+/// The first string between `open` and its matching close. GNU ld quotes as `like this'.
+fn quoted(text: &str, open: char) -> Option<&str> {
+    let close = match open {
+        '`' => '\'',
+        other => other,
+    };
+    let (_, rest) = text.split_once(open)?;
+    let (inner, _) = rest.split_once(close)?;
+    Some(inner)
+}
+
+fn quoted_all(text: &str, quote: char) -> Vec<&str> {
+    text.split(quote).skip(1).step_by(2).collect()
+}
+
+/// The `>>> ` lines lld puts under an error, starting with `label`
+fn details<'a>(
+    lines: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    label: &str,
+) -> Vec<String> {
+    let mut found = vec![];
+    while let Some(next) = lines.next_if(|l| l.trim_start().starts_with(">>> ")) {
+        let detail = next.trim_start().trim_start_matches(">>> ").trim();
+        if let Some(detail) = detail.strip_prefix(label) {
+            found.push(crate::symbol::demangle_text(detail));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOO: &str = "__ZN7harness3foo17h0123456789abcdefE";
+
+    fn problems(stderr: &str) -> Vec<LinkProblem> {
+        parse_link_errors(stderr)
+            .into_iter()
+            .map(|d| d.problem)
+            .collect()
+    }
+
+    #[test]
+    fn parses_ld64() {
+        let stderr = format!(
+            "ld: warning: no platform load command found in 'stub.o', assuming: macOS\n\
+             Undefined symbols for architecture arm64:\n  \
+             \"{FOO}\", referenced from:\n      \
+             _main in harness.o\n\
+             ld: symbol(s) not found for architecture arm64\n\
+             duplicate symbol '_bar' in:\n    \
+             patch.o\n    \
+             stub.o\n\
+             ld: 1 duplicate symbols\n\
+             ld: invalid use of ADRP in '_baz' to '{FOO}'\n"
+        );
+
+        let diagnostics = parse_link_errors(&stderr);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[1..]
+                .iter()
+                .map(|d| d.problem.clone())
+                .collect::<Vec<_>>(),
+            [
+                LinkProblem::UndefinedSymbol {
+                    symbol: "harness::foo".to_string(),
+                    referenced_from: vec!["_main".to_string()],
+                },
+                LinkProblem::DuplicateSymbol {
+                    symbol: "_bar".to_string(),
+                    defined_in: vec!["patch.o".to_string(), "stub.o".to_string()],
+                },
+                LinkProblem::PageViolation {
+                    from: Some("_baz".to_string()),
+                    to: Some("harness::foo".to_string()),
+                    message: "invalid use of ADRP in '_baz' to 'harness::foo'".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_gnu_ld_and_lld() {
+        let gnu = "/usr/bin/ld: patch.o: in function `bar':\n\
+                   lib.rs:(.text.bar+0x5): undefined reference to `_ZN7harness3foo17h0123456789abcdefE'\n\
+                   /usr/bin/ld: b.o:(.text+0x0): multiple definition of `baz'; a.o:(.text+0x0): first defined here\n\
+                   collect2: error: ld returned 1 exit status\n";
+        assert_eq!(
+            problems(gnu),
+            [
+                LinkProblem::UndefinedSymbol {
+                    symbol: "harness::foo".to_string(),
+                    referenced_from: vec!["bar".to_string()],
+                },
+                LinkProblem::DuplicateSymbol {
+                    symbol: "baz".to_string(),
+                    defined_in: vec!["b.o:(.text+0x0)".to_string(), "a.o:(.text+0x0)".to_string()],
+                },
+            ]
+        );
+
+        let lld = "ld.lld: error: undefined symbol: harness::foo\n\
+                   >>> referenced by lib.rs:3\n\
+                   >>>               patch.o:(bar)\n\
+                   ld.lld: error: patch.o:(.text+0x4): relocation R_AARCH64_CALL26 out of range: 268435456 is not in [-134217728, 134217727]; references foo\n";
+        assert_eq!(
+            problems(lld),
+            [
+                LinkProblem::UndefinedSymbol {
+                    symbol: "harness::foo".to_string(),
+                    referenced_from: vec!["lib.rs:3".to_string()],
+                },
+                LinkProblem::RelocationOutOfRange {
+                    symbol: Some("foo".to_string()),
+                    message: "error: patch.o:(.text+0x4): relocation R_AARCH64_CALL26 out of range: 268435456 is not in [-134217728, 134217727]; references foo".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_explicit_errors_fail_the_link() {
+        let stderr = "clang: warning: argument unused during compilation: '-nopie'\n\
+                      ld: warning: no platform load command found in 'stub.o', assuming: macOS\n\
+                      /usr/bin/ld: note: 'foo' is defined in DSO libfoo.so\n\
+                      Linking patch with 3 objects\n\
+                      clang: error: unable to execute command: Killed\n\
+                      ld.lld: error: cannot open patch.o: No such file or directory\n";
+
+        let severities = parse_link_errors(stderr)
+            .into_iter()
+            .map(|d| d.severity)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            severities,
+            [
+                Severity::Warning,
+                Severity::Warning,
+                Severity::Note,
+                Severity::Note,
+                Severity::Error,
+                Severity::Error,
+            ]
+        );
+    }
+}
@@ -2,16 +2,43 @@ use anyhow::{Context, Result};
 use itertools::Itertools;
 use memmap::{Mmap, MmapOptions};
 use object::{
-    read::File, Architecture, BinaryFormat, Endianness, Object, ObjectSection, ObjectSymbol,
-    Relocation, RelocationTarget, SectionIndex,
+    read::{File, Section},
+    Architecture, BinaryFormat, Endianness, Object, ObjectSection, ObjectSymbol, Relocation,
+    RelocationFlags, RelocationKind, RelocationTarget, SectionIndex, SectionKind, SymbolKind,
 };
+use ouroboros::self_referencing;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{cmp::Ordering, ffi::OsStr, fs, ops::Deref, path::PathBuf};
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::Path,
+    time::{Duration, Instant},
 };
 use tokio::process::Command;
 
+use crate::report::ReloadReport;
+use crate::symbol::{demangle, demangle_text};
+
+mod fingerprint;
+mod global;
+mod identity;
+mod impact;
+mod inspect;
+mod lines;
+mod resolve;
+mod signature;
+
+use fingerprint::{clear_generations, latest_generation, FileFingerprints, FingerprintTable};
+pub use fingerprint::{commit_pending_generation, discard_pending_generation};
+use global::{dangling_references, diff_symbols, GlobalChange, GlobalSymbols};
+pub use global::{DanglingReason, DanglingRef};
+use identity::{is_anonymous, is_local_label, pair_anonymous, AnonIdentities, AnonSymbols};
+use impact::Impact;
+pub use inspect::DiffArgs;
+pub use lines::{SourceRange, LINE_INFO_ENV};
+use resolve::{Aarch64Insn, TargetResolver, UnresolvedReloc};
+
 #[tokio::test]
 async fn _attempt_partial_link() {
     let addr: u64 = std::fs::read_to_string(workspace_dir().join("harnessaddr.txt"))
@@ -25,15 +52,38 @@ async fn _attempt_partial_link() {
     attempt_partial_link(addr, patch_target, workspace_dir().join("partial.o")).await;
 }
 
-pub async fn attempt_partial_link(proc_main_addr: u64, patch_target: PathBuf, out_path: PathBuf) {
-    let mut object = ObjectDiff::new().unwrap();
-    object.load().unwrap();
+pub async fn attempt_partial_link(
+    proc_main_addr: u64,
+    patch_target: PathBuf,
+    out_path: PathBuf,
+) -> ReloadReport {
+    // The driver only makes this the next generation once the app has loaded the patch
+    let object = ObjectDiff::new().unwrap();
+    object.fingerprints.save_pending().unwrap();
+    object.timings.print();
+
+    // Classify the changes and drop them somewhere the driver can pick them up.
+    // We're running as the linker here so nobody is going to see our stdout.
+    let impact = object.impact();
+    let mut report = ReloadReport::new(&object.changes, &object.dangling);
+    report.hot_functions = impact.hot_functions(object.modified_symbols.iter().map(|s| s.as_str()));
+    report.warnings = object
+        .unresolved
+        .iter()
+        .map(|u| format!("couldn't resolve relocation in {u}"))
+        .collect();
+    if std::env::var_os(LINE_INFO_ENV).is_some() {
+        report.source_ranges = object.source_ranges();
+    }
+    report.save(&crate::report::report_path()).unwrap();
 
     let all_exports = object
-        .new
-        .iter()
-        .flat_map(|(_, f)| f.file.exports().unwrap())
-        .map(|e| e.name().to_utf8())
+        .fingerprints
+        .files
+        .values()
+        .flat_map(|f| f.symbols.iter())
+        .filter(|(_, s)| s.exported)
+        .map(|(name, _)| name.as_str())
         .collect::<HashSet<_>>();
 
     let mut adrp_imports = HashSet::new();
@@ -50,17 +100,14 @@ pub async fn attempt_partial_link(proc_main_addr: u64, patch_target: PathBuf, ou
         println!("No modified symbols");
     }
 
+    // Which components are going to pick up the new code
     let mut modified_log = String::new();
-    for m in modified_symbols.iter() {
-        // if m.starts_with("l") {
-        //     continue;
-        // }
-
-        let path = object.find_path_to_main(m);
-        println!("m: {m}");
-        println!("path: {path:#?}\n");
-        modified_log.push_str(&format!("{m}\n"));
-        modified_log.push_str(&format!("{path:#?}\n"));
+    for (m, entry_points) in impact.affected(modified_symbols.iter().copied()) {
+        let entry_points = entry_points.iter().map(|e| demangle(e)).collect_vec();
+        println!("m: {}", demangle(&m));
+        println!("entry points: {entry_points:#?}\n");
+        modified_log.push_str(&format!("{}\n", demangle(&m)));
+        modified_log.push_str(&format!("{entry_points:#?}\n"));
     }
     std::fs::write(workspace_dir().join("modified_symbols.txt"), modified_log).unwrap();
 
@@ -73,25 +120,22 @@ pub async fn attempt_partial_link(proc_main_addr: u64, patch_target: PathBuf, ou
     // Figure out which symbols are required from *existing* code
     // We're going to create a stub `.o` file that satisfies these by jumping into the original code via a dynamic lookup / and or literally just manually doing it
     for fil in modified.iter() {
-        let f = object
-            .new
-            .get(fil.0.file_name().unwrap().to_str().unwrap())
-            .unwrap();
-
-        for i in f.file.imports().unwrap() {
-            if all_exports.contains(i.name().to_utf8()) {
-                adrp_imports.insert(i.name().to_utf8());
+        let f = LoadedFile::open(fil.0.clone()).unwrap();
+
+        for i in f.file().imports().unwrap() {
+            if let Some(name) = all_exports.get(i.name().to_utf8()) {
+                adrp_imports.insert(*name);
             }
         }
 
-        for e in f.file.exports().unwrap() {
-            satisfied_exports.insert(e.name().to_utf8());
+        for e in f.file().exports().unwrap() {
+            satisfied_exports.insert(e.name().to_utf8().to_string());
         }
     }
 
     // Remove any imports that are indeed satisifed
     for s in satisfied_exports.iter() {
-        adrp_imports.remove(s);
+        adrp_imports.remove(s.as_str());
     }
 
     // Assemble the stub
@@ -114,9 +158,20 @@ pub async fn attempt_partial_link(proc_main_addr: u64, patch_target: PathBuf, ou
         .await
         .unwrap();
 
-    let err = String::from_utf8_lossy(&out.stderr);
-    println!("err: {err}");
+    let err = demangle_text(&String::from_utf8_lossy(&out.stderr));
+    for diagnostic in crate::diagnostics::parse_link_errors(&err) {
+        println!("partial link: {diagnostic}");
+    }
     std::fs::write(workspace_dir().join("link_errs_partial.txt"), &*err).unwrap();
+
+    report
+}
+
+/// Fingerprint the objects of a full build and make them the baseline for the next patch
+pub fn save_baseline() -> Result<()> {
+    clear_generations();
+    let dir = workspace_dir().join("data").join("incremental-new");
+    FingerprintTable::from_dir(&dir, &FingerprintTable::default())?.save(0)
 }
 
 fn make_stub_file(
@@ -147,276 +202,464 @@ fn make_stub_file(
     .unwrap()
 }
 
-struct ObjectDiff {
-    old: BTreeMap<String, LoadedFile>,
-    new: BTreeMap<String, LoadedFile>,
-    modified_files: HashMap<PathBuf, HashSet<String>>,
-    modified_symbols: HashSet<String>,
-    parents: HashMap<String, HashSet<String>>,
+/// What happened to a symbol between the old and new build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
 }
 
-impl ObjectDiff {
-    fn new() -> Result<Self> {
-        Ok(Self {
-            old: LoadedFile::from_dir(&workspace_dir().join("data").join("incremental-old"))?,
-            new: LoadedFile::from_dir(&workspace_dir().join("data").join("incremental-new"))?,
-            modified_files: Default::default(),
-            modified_symbols: Default::default(),
-            parents: Default::default(),
-        })
+/// The rough category of a symbol, decided by the kind of section it lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolClass {
+    Function,
+    Static,
+    ThreadLocal,
+    Other,
+
+    /// Read-only data like string literals - new code just points at the new copy
+    Constant,
+}
+
+impl SymbolClass {
+    fn of_section(kind: SectionKind) -> Self {
+        match kind {
+            SectionKind::Text => SymbolClass::Function,
+            SectionKind::Data | SectionKind::UninitializedData | SectionKind::Common => {
+                SymbolClass::Static
+            }
+            SectionKind::ReadOnlyData
+            | SectionKind::ReadOnlyDataWithRel
+            | SectionKind::ReadOnlyString => SymbolClass::Constant,
+            SectionKind::Tls | SectionKind::UninitializedTls | SectionKind::TlsVariables => {
+                SymbolClass::ThreadLocal
+            }
+            _ => SymbolClass::Other,
+        }
     }
+}
 
-    fn load(&mut self) -> Result<()> {
-        let num_right = self.new.len();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolChange {
+    pub kind: ChangeKind,
+    pub class: SymbolClass,
+}
 
-        let keys = self.new.keys().cloned().collect::<Vec<_>>();
-        for (idx, f) in keys.iter().enumerate() {
-            println!("----- {:?} {}/{} -----", f, idx, num_right);
+struct ObjectDiff {
+    /// The directory holding the objects of the new build
+    dir: PathBuf,
 
-            let changed_before = self.modified_symbols.len();
-            self.load_file(f)?;
-            let changed_after = self.modified_symbols.len();
+    /// Fingerprints of the new build
+    fingerprints: FingerprintTable,
 
-            if changed_after > changed_before {
-                println!("❌ -> {}", changed_after - changed_before);
-            }
-        }
+    modified_files: BTreeMap<PathBuf, BTreeSet<String>>,
+    modified_symbols: BTreeSet<String>,
+    parents: BTreeMap<String, BTreeSet<String>>,
 
-        Ok(())
-    }
-
-    /// Walk the call  to find the path to the main function
-    fn find_path_to_main(&self, name: &str) -> Vec<String> {
-        let mut path = Vec::new();
-        let mut visited = std::collections::HashSet::new();
-
-        // Helper function for DFS with backtracking
-        fn dfs(
-            current: &str,
-            path: &mut Vec<String>,
-            visited: &mut std::collections::HashSet<String>,
-            parents: &std::collections::HashMap<String, HashSet<String>>,
-        ) -> bool {
-            // If we've found main, we're done
-            if current.ends_with("_main") {
-                path.push(current.to_string());
-                return true;
-            }
+    /// Every symbol that was added, modified, or removed, along with what kind of symbol it is
+    changes: HashMap<String, SymbolChange>,
 
-            // Mark current node as visited
-            visited.insert(current.to_string());
-            path.push(current.to_string());
-
-            // Check all parents of the current node
-            if let Some(parent_nodes) = parents.get(current) {
-                for parent in parent_nodes {
-                    if !visited.contains(parent) {
-                        if dfs(parent, path, visited, parents) {
-                            return true;
-                        }
-                    }
-                }
-            }
+    /// Relocations in the new build that we couldn't trace to a symbol
+    unresolved: Vec<UnresolvedReloc>,
 
-            // If no path is found through this node, backtrack
-            path.pop();
+    /// Live code that still references functions the new build removed
+    dangling: Vec<DanglingRef>,
 
-            false
-        }
+    timings: DiffTimings,
+}
 
-        // Start DFS from the given name
-        dfs(name, &mut path, &mut visited, &self.parents);
+/// How long each phase of the diff took
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiffTimings {
+    pub fingerprint: PhaseTiming,
 
-        path
-    }
+    /// Pooling the new build's symbols across its objects
+    pub pooling: PhaseTiming,
+
+    /// The same for the baseline
+    pub baseline: PhaseTiming,
+
+    /// Comparing the pools and looking for dangling references, counted in symbols
+    pub diff: PhaseTiming,
+}
+
+/// `work` is the time spent summed across every thread, so `work / wall` is roughly how much we
+/// got out of running the phase in parallel
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PhaseTiming {
+    pub wall: Duration,
+    pub work: Duration,
+    pub items: usize,
+}
+
+impl PhaseTiming {
+    /// Map over `items` on the rayon pool, keeping the results in order
+    pub fn par_map<T: Sync, U: Send>(
+        items: &[T],
+        f: impl Fn(&T) -> U + Sync,
+    ) -> (Vec<U>, PhaseTiming) {
+        let started = Instant::now();
+        let (out, work): (Vec<U>, Vec<Duration>) = items
+            .par_iter()
+            .map(|item| {
+                let started = Instant::now();
+                let out = f(item);
+                (out, started.elapsed())
+            })
+            .unzip();
 
-    fn load_file(&mut self, name: &str) -> Result<()> {
-        let new = &self.new[name];
-        let Some(old) = self.old.get(name) else {
-            self.modified_files.entry(new.path.clone()).or_default();
-            return Ok(());
+        let timing = PhaseTiming {
+            wall: started.elapsed(),
+            work: work.into_iter().sum(),
+            items: items.len(),
         };
 
-        let mut changed_list = HashSet::new();
-        for section in new.file.sections() {
-            let n = section.name().unwrap();
-            if n == "__text"
-                || n == "__const"
-                || n.starts_with("__literal")
-                || n == "__eh_frame"
-                || n == "__compact_unwind"
-                || n == "__gcc_except_tab"
-                || n == "__common"
-                || n == "__bss"
-            {
-                changed_list.extend(self.accumulate_changed(&old, &new, section.index()));
-            } else {
-                println!("Skipping section: {n}");
-            }
-        }
+        (out, timing)
+    }
 
-        for c in changed_list.iter() {
-            if !c.starts_with("l") && !c.starts_with("ltmp") {
-                self.modified_symbols.insert(c.to_string());
-            } else {
-                let mod_name = format!("{c}_{name}");
-                self.modified_symbols.insert(mod_name);
-            }
+    /// Time a phase that runs on a single thread
+    pub fn serial<U>(items: usize, f: impl FnOnce() -> U) -> (U, PhaseTiming) {
+        let started = Instant::now();
+        let out = f();
+        let wall = started.elapsed();
+
+        (
+            out,
+            PhaseTiming {
+                wall,
+                work: wall,
+                items,
+            },
+        )
+    }
+
+    pub fn speedup(&self) -> f64 {
+        self.work.as_secs_f64() / self.wall.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl DiffTimings {
+    pub fn print(&self) {
+        println!("diff timings ({} threads):", rayon::current_num_threads());
+        for (label, phase, unit) in [
+            ("fingerprint", self.fingerprint, "objects"),
+            ("pooling", self.pooling, "objects"),
+            ("baseline", self.baseline, "objects"),
+            ("diff", self.diff, "symbols"),
+        ] {
+            println!(
+                "  {label}: {} {unit} in {:?} ({:?} of work, {:.1}x)",
+                phase.items,
+                phase.wall,
+                phase.work,
+                phase.speedup()
+            );
         }
+    }
+}
 
-        for (child, parents) in new.parents.iter() {
-            let child_name = match child.starts_with("l") {
-                true => format!("{child}_{name}"),
-                false => child.to_string(),
-            };
+impl ObjectDiff {
+    /// Diff the newest build against the generation right before it
+    fn new() -> Result<Self> {
+        let latest = latest_generation().context("No baseline fingerprints to diff against")?;
+        Self::against(latest)
+    }
 
-            for parent in parents {
-                let p_name = match parent.starts_with("l") {
-                    true => format!("{parent}_{name}"),
-                    false => parent.to_string(),
-                };
+    /// Diff the newest build against any earlier generation, like `cargo hotreload diff --against`
+    fn against(generation: usize) -> Result<Self> {
+        let baseline = FingerprintTable::load(generation)?;
 
-                self.parents
-                    .entry(child_name.clone())
-                    .or_default()
-                    .insert(p_name);
-            }
-        }
+        // Objects that rustc didn't touch are most likely in the latest generation
+        let cache = match latest_generation() {
+            Some(latest) if latest != generation => FingerprintTable::load(latest)?,
+            _ => baseline.clone(),
+        };
+
+        let dir = workspace_dir().join("data").join("incremental-new");
+        let (fingerprints, timing) = FingerprintTable::from_dir_timed(&dir, &cache)?;
 
-        Ok(())
+        let mut diff = Self::between(dir, fingerprints, &baseline);
+        diff.timings.fingerprint = timing;
+
+        Ok(diff)
     }
 
-    fn accumulate_changed(
-        &self,
-        old: &LoadedFile,
-        new: &LoadedFile,
-        section_idx: SectionIndex,
-    ) -> HashSet<&'static str> {
-        let mut local_modified = HashSet::new();
+    /// Diff an already fingerprinted build against a baseline
+    fn between(dir: PathBuf, fingerprints: FingerprintTable, baseline: &FingerprintTable) -> Self {
+        let mut diff = Self {
+            dir,
+            fingerprints,
+            modified_files: Default::default(),
+            modified_symbols: Default::default(),
+            parents: Default::default(),
+            changes: Default::default(),
+            unresolved: Default::default(),
+            dangling: Default::default(),
+            timings: Default::default(),
+        };
+
+        diff.load(baseline);
+        diff
+    }
 
-        // Accumulate modified symbols using masking in functions
-        let relocated_new = acc_symbols(&new.file, section_idx);
-        let mut relocated_old = acc_symbols(&old.file, section_idx)
-            .into_iter()
-            .map(|f| (f.name, f))
-            .collect::<HashMap<_, _>>();
+    fn load(&mut self, baseline: &FingerprintTable) {
+        let (new, timing) = GlobalSymbols::new(&self.fingerprints);
+        self.timings.pooling = timing;
 
-        for right in relocated_new {
-            let Some(left) = relocated_old.remove(right.name) else {
-                local_modified.insert(right.name);
-                continue;
-            };
+        let (old, timing) = GlobalSymbols::new(baseline);
+        self.timings.baseline = timing;
 
-            // If the contents of the assembly changed, track it
-            if !compare_masked(old.file, new.file, &left, &right) {
-                local_modified.insert(left.name);
-                local_modified.insert(right.name);
+        let ((diff, dangling), timing) = PhaseTiming::serial(new.symbols.len(), || {
+            let diff = diff_symbols(&old, &new);
+            let dangling = dangling_references(&old, &new, &diff);
+            (diff, dangling)
+        });
+        self.timings.diff = timing;
+        self.dangling = dangling;
+
+        for GlobalChange {
+            name,
+            change,
+            files,
+        } in diff.changes
+        {
+            // Removed symbols aren't part of the new objects so there's nothing to link
+            if change.kind != ChangeKind::Removed {
+                self.modified_symbols.insert(name.clone());
+                for file in files {
+                    self.modified_files
+                        .entry(self.dir.join(file))
+                        .or_default()
+                        .insert(name.clone());
+                }
             }
+
+            self.changes.insert(name, change);
+        }
+
+        for (file, symbols) in self.modified_files.iter() {
+            println!("❌ {:?} -> {}", file.file_name().unwrap(), symbols.len());
         }
 
-        local_modified
+        self.parents = new.parents;
+        self.unresolved = new.unresolved;
+    }
+
+    /// The reverse call graph of the new build
+    fn impact(&self) -> Impact<'_> {
+        Impact::new(&self.parents)
+    }
+}
+
+/// The bytes of an object: mapped straight from disk, or pulled out of an archive
+enum ObjectData {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for ObjectData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ObjectData::Mapped(mmap) => mmap,
+            ObjectData::Owned(bytes) => bytes,
+        }
     }
 }
 
-/// A file loaded into memory with its analysis
+/// A file mapped into memory along with the object parsed out of it
 ///
-/// We leak the module to make it easier to deal with its contents
+/// The parsed object borrows from the mapping, so the two live and die together
+#[self_referencing]
 struct LoadedFile {
-    path: PathBuf,
-    open_file: std::fs::File,
-    mmap: &'static Mmap,
+    data: ObjectData,
 
-    file: &'static File<'static>,
-
-    // symbol -> symbols
-    parents: HashMap<&'static str, HashSet<&'static str>>,
+    #[borrows(data)]
+    #[covariant]
+    file: File<'this>,
 }
 
 impl LoadedFile {
-    fn from_dir(dir: &Path) -> anyhow::Result<BTreeMap<String, Self>> {
-        std::fs::read_dir(dir)?
-            .into_iter()
-            .flatten()
-            .filter(|e| e.path().extension() == Some(OsStr::new("o")))
-            .map(|e| {
-                Ok((
-                    e.path().file_name().unwrap().to_string_lossy().to_string(),
-                    Self::new(e.path())?,
-                ))
-            })
-            .collect()
+    fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let open_file = std::fs::File::open(&path)?;
+        let mmap = unsafe { MmapOptions::new().map(&open_file)? };
+
+        Self::parse(ObjectData::Mapped(mmap)).with_context(|| format!("Failed to parse {path:?}"))
     }
 
-    fn new(path: PathBuf) -> anyhow::Result<Self> {
-        let open_file = std::fs::File::open(&path)?;
-        let mmap = unsafe { MmapOptions::new().map(&open_file).unwrap() };
-        let mmap: &'static Mmap = Box::leak(Box::new(mmap));
-        let f = File::parse(mmap.deref() as &[u8])?;
-        let file: &'static File<'static> = Box::leak(Box::new(f));
+    fn from_bytes(bytes: Vec<u8>) -> anyhow::Result<Self> {
+        Self::parse(ObjectData::Owned(bytes))
+    }
 
-        // Set up the data structures
-        let mut sym_tab = HashMap::<&'static str, RelocatedSymbol<'static>>::new();
-        let mut parents = HashMap::<&'static str, HashSet<&'static str>>::new();
+    fn parse(data: ObjectData) -> anyhow::Result<Self> {
+        Ok(LoadedFileTryBuilder {
+            data,
+            file_builder: |data| File::parse(data.deref()),
+        }
+        .try_build()?)
+    }
+
+    fn file(&self) -> &File<'_> {
+        self.borrow_file()
+    }
+
+    /// Walk the relocations to find who references who
+    fn call_graph(&self) -> CallGraph<'_> {
+        let file = self.file();
+        let resolver = TargetResolver::new(file);
 
         // Build the symbol table
+        let mut graph = CallGraph::default();
+        let mut sym_tab = HashMap::<&str, RelocatedSymbol>::new();
         for sect in file.sections() {
-            for r in acc_symbols(&file, sect.index()) {
+            let (symbols, orphans) = split_section(file, sect.index());
+            for r in symbols {
                 sym_tab.insert(r.name, r);
             }
+            graph.unresolved.extend(orphans);
         }
 
-        // Create a map of address -> symbol so we can resolve the section of a symbol
-        let local_defs = file
-            .symbols()
-            .filter(|s| s.is_definition())
-            .map(|s| (s.address(), s.name().unwrap()))
-            .collect::<BTreeMap<_, _>>();
-
-        // Build the call graph by walking the relocations
         // We keep track of what calls whata
         for (sym_name, sym) in sym_tab.iter() {
-            let sym_section = file.section_by_index(sym.section).unwrap();
-            let sym_data = sym_section.data().unwrap();
-
             for (addr, reloc) in sym.relocations.iter() {
-                let target = match symbol_name_of_relo(file, reloc.target()) {
-                    Some(name) => name,
-                    None => {
-                        let addend = u64::from_le_bytes(
-                            sym_data[*addr as usize..(*addr + 8) as usize]
-                                .try_into()
-                                .unwrap(),
-                        );
-                        local_defs.get(&addend).unwrap()
+                match resolver.resolve(file, sym, *addr, reloc) {
+                    Ok(target) => {
+                        graph.parents.entry(target).or_default().insert(sym_name);
                     }
-                };
-
-                parents.entry(target).or_default().insert(sym_name);
+                    Err(reason) => graph.unresolved.push(UnresolvedReloc {
+                        symbol: sym_name.to_string(),
+                        offset: *addr,
+                        reason,
+                    }),
+                }
             }
         }
 
-        Ok(Self {
-            path,
-            open_file,
-            mmap,
-            file,
-            parents,
-        })
+        graph
+            .unresolved
+            .sort_by_key(|u| (u.symbol.clone(), u.offset));
+        graph
     }
 }
 
+#[derive(Default)]
+struct CallGraph<'a> {
+    /// symbol -> the symbols that reference it
+    parents: HashMap<&'a str, HashSet<&'a str>>,
+
+    /// Relocations we couldn't trace back to a symbol
+    unresolved: Vec<UnresolvedReloc>,
+}
+
 /// A function with its relevant relocations to be used for masked comparisons
 struct RelocatedSymbol<'a> {
     name: &'a str,
     /// offset within the section
     offset: usize,
+
+    /// How much of the section the symbol covers. Usually the length of `data`, except for
+    /// zero-fill sections which have no data.
+    size: usize,
     data: &'a [u8],
-    relocations: &'a [(u64, Relocation)],
+    relocations: Vec<(u64, Relocation)>,
     sym: object::Symbol<'a, 'a>,
     section: SectionIndex,
 }
 
-fn acc_symbols<'a>(new: &'a File<'a>, section_idx: SectionIndex) -> Vec<RelocatedSymbol<'a>> {
+/// Whether the section holds code or data we care about diffing, as opposed to debug info and
+/// other metadata
+fn should_diff(section: &Section) -> bool {
+    match section.kind() {
+        SectionKind::Text
+        | SectionKind::Data
+        | SectionKind::ReadOnlyData
+        | SectionKind::ReadOnlyDataWithRel
+        | SectionKind::ReadOnlyString
+        | SectionKind::UninitializedData
+        | SectionKind::Common
+        | SectionKind::Tls
+        | SectionKind::UninitializedTls
+        | SectionKind::TlsVariables => true,
+
+        // Unwind info and exception tables
+        _ => matches!(
+            section.name(),
+            Ok("__eh_frame" | "__compact_unwind" | "__gcc_except_tab")
+                | Ok(".eh_frame" | ".gcc_except_table")
+        ),
+    }
+}
+
+/// Split a section up into the symbols defined in it.
+///
+/// rustc puts every function in its own `.text.<sym>` section on ELF, so there the section itself
+/// is the function and we don't have to guess where it ends.
+fn section_symbols<'a>(file: &'a File<'a>, section_idx: SectionIndex) -> Vec<RelocatedSymbol<'a>> {
+    split_section(file, section_idx).0
+}
+
+/// Split a section up into its symbols, along with the relocations that don't fall in any of them
+fn split_section<'a>(
+    file: &'a File<'a>,
+    section_idx: SectionIndex,
+) -> (Vec<RelocatedSymbol<'a>>, Vec<UnresolvedReloc>) {
+    let section = file.section_by_index(section_idx).unwrap();
+
+    if matches!(
+        SymbolClass::of_section(section.kind()),
+        SymbolClass::Static | SymbolClass::Constant | SymbolClass::ThreadLocal
+    ) {
+        return (data_symbols(file, &section), vec![]);
+    }
+
+    if file.format() == BinaryFormat::Elf
+        && section.kind() == SectionKind::Text
+        && section.name().is_ok_and(|n| n.starts_with(".text."))
+    {
+        if let Some(sym) = function_section_symbol(file, &section) {
+            return (vec![sym], vec![]);
+        }
+    }
+
+    acc_symbols(file, section_idx)
+}
+
+/// The function that owns an ELF function section, spanning the whole section
+fn function_section_symbol<'a>(
+    file: &'a File<'a>,
+    section: &Section<'a, 'a>,
+) -> Option<RelocatedSymbol<'a>> {
+    let sym = file
+        .symbols()
+        .filter(|s| s.section_index() == Some(section.index()))
+        .filter(|s| s.kind() == SymbolKind::Text && s.address() == section.address())
+        .min_by_key(|s| (s.is_local(), s.index().0))?;
+
+    let relocations = section
+        .relocations()
+        .sorted_by(|a, b| a.0.cmp(&b.0).reverse())
+        .collect::<Vec<_>>();
+
+    let data = section.data().ok()?;
+    Some(RelocatedSymbol {
+        name: sym.name().ok()?,
+        offset: 0,
+        size: data.len(),
+        data,
+        relocations,
+        sym,
+        section: section.index(),
+    })
+}
+
+fn acc_symbols<'a>(
+    new: &'a File<'a>,
+    section_idx: SectionIndex,
+) -> (Vec<RelocatedSymbol<'a>>, Vec<UnresolvedReloc>) {
     let mut syms = vec![];
 
     let section = new.section_by_index(section_idx).unwrap();
@@ -434,67 +677,35 @@ fn acc_symbols<'a>(new: &'a File<'a>, section_idx: SectionIndex) -> Vec<Relocate
         })
         .collect::<Vec<_>>();
 
-    // todo!!!!!! jon: don't leak this lol
-    let relocations = section
+    // Sorted in reverse to match the order we walk the symbols in
+    let mut relocations = section
         .relocations()
         .sorted_by(|a, b| a.0.cmp(&b.0).reverse())
-        .collect::<Vec<_>>()
-        .leak();
+        .peekable();
 
     let data = section.data().unwrap();
 
-    // No symbols, no symbols,
-    if sorted.is_empty() {
-        return vec![];
-    }
-
     // The end of the currently analyzed function
     let mut func_end = section.size() as usize;
 
-    // The idx into the relocation list that applies to this function. We'll march these
-    let mut reloc_idx = 0;
-
     // Walk in reverse so we can use the text_length as the initial backstop and to match relocation order
     for sym in sorted.into_iter().rev() {
         let sym_offset = sym.address() - section.address();
 
-        // Move the head/tail to include the sub-slice of the relocations that apply to this symbol
-        let mut reloc_start = None;
-        loop {
-            // If we've reached the end of the relocations then we're done
-            if reloc_idx == relocations.len() {
-                break;
-            }
-
-            // relocations behind the symbol start don't apply
-            if relocations[reloc_idx].0 < sym_offset {
-                break;
-            }
-
-            // Set the head to the first relocation that applies
-            if reloc_start.is_none() {
-                reloc_start = Some(reloc_idx);
-            }
-
-            reloc_idx += 1;
-        }
+        // Take the relocations that apply to this symbol - anything behind the symbol start
+        // belongs to the symbols before it
+        let relocations = relocations
+            .peeking_take_while(|(addr, _)| *addr >= sym_offset)
+            .collect::<Vec<_>>();
 
         // Identify the instructions that apply to this symbol
-        let data = match reloc_start {
-            Some(_start) => &data[sym_offset as usize..func_end],
-            _ => &[],
-        };
-
-        // Identify the relocations that apply to this symbol
-        let relocations = match reloc_start {
-            Some(start) => &relocations[start..reloc_idx],
-            None => &[],
-        };
+        let data = &data[sym_offset as usize..func_end];
 
         syms.push(RelocatedSymbol {
             name: sym.name().unwrap(),
             sym,
             offset: sym_offset as usize,
+            size: data.len(),
             data,
             relocations,
             section: section_idx,
@@ -503,116 +714,216 @@ fn acc_symbols<'a>(new: &'a File<'a>, section_idx: SectionIndex) -> Vec<Relocate
         func_end = sym_offset as usize;
     }
 
-    assert_eq!(reloc_idx, relocations.len());
+    // Whatever is left sits in front of the first symbol, so there's nobody to attribute it to
+    let section_name = section.name().unwrap_or_default();
+    let orphans = relocations
+        .map(|(offset, _)| UnresolvedReloc {
+            symbol: section_name.to_string(),
+            offset,
+            reason: "not covered by any symbol".to_string(),
+        })
+        .collect();
 
-    syms
+    (syms, orphans)
 }
 
-/// Compare two sets of bytes, masking out the bytes that are not part of the symbol
-/// This is so we can compare functions with different relocations
-fn compare_masked<'a>(
-    old: &impl Object<'a>,
-    new: &impl Object<'a>,
-    left: &RelocatedSymbol,
-    right: &RelocatedSymbol,
-) -> bool {
-    // Make sure the relocations are the same length
-    if left.relocations.len() != right.relocations.len() {
-        return false;
-    }
-
-    // Make sure the data is the same length
-    // If the size changed then the instructions are different (well, not necessarily, but enough)
-    if left.data.len() != right.data.len() {
-        return false;
-    }
-
-    // Make sure the names match
-    if left.name != right.name {
-        return false;
-    }
-
-    // We're going to walk from relocation target to target, but since there's no implicit target
-    // to start with, we simply use the end of the data
-    let mut last = left.data.len();
-
-    // Ensure the relocations point to the same symbol
-    // Data symbols are special ... todo
-    //
-    // relocations are in reverse order, so we can also compare the data as we go
-    for x in 0..left.relocations.len() {
-        // Grab the reloc
-        let (l_addr, left_reloc): &(u64, Relocation) = &left.relocations[x];
-        let (_r_addr, right_reloc): &(u64, Relocation) = &right.relocations[x];
-
-        // The targets might not be same by index but should resolve to the same *name*
-        let left_target: RelocationTarget = left_reloc.target();
-        let right_target: RelocationTarget = right_reloc.target();
-
-        // Use the name of the symbol to compare
-        // todo: decide if it's internal vs external
-        let left_name = symbol_name_of_relo(old, left_target);
-        let right_name = symbol_name_of_relo(new, right_target);
-        let (Some(left_name), Some(right_name)) = (left_name, right_name) else {
-            continue;
+/// Split a data section up by symbol extents.
+///
+/// Unlike code, nothing says data symbols have relocations marking where they start, so we go by
+/// their sizes and fall back to the distance to the next symbol. Zero-fill sections don't have any
+/// bytes at all, so for those the size is all there is to compare.
+fn data_symbols<'a>(file: &'a File<'a>, section: &Section<'a, 'a>) -> Vec<RelocatedSymbol<'a>> {
+    let data = section.data().unwrap_or_default();
+
+    // Labels like `ltmp1` sit on top of the first real symbol - keep one symbol per address,
+    // preferring a real name
+    let sorted = file
+        .symbols()
+        .filter(|s| s.section_index() == Some(section.index()))
+        .filter(|s| s.kind() != SymbolKind::Section && s.name().is_ok())
+        .sorted_by_key(|s| {
+            let anonymous = is_anonymous(s.name().unwrap());
+            (s.address(), anonymous, s.is_local(), s.index().0)
+        })
+        .dedup_by(|a, b| a.address() == b.address())
+        .collect::<Vec<_>>();
+
+    let mut relocations = section
+        .relocations()
+        .sorted_by_key(|(offset, _)| *offset)
+        .peekable();
+
+    let starts = sorted
+        .iter()
+        .map(|s| s.address() - section.address())
+        .collect::<Vec<_>>();
+
+    let mut syms = vec![];
+    for (idx, sym) in sorted.into_iter().enumerate() {
+        let start = starts[idx];
+        let next = starts.get(idx + 1).copied().unwrap_or(section.size());
+        let end = match sym.size() {
+            0 => next,
+            size => (start + size).min(next),
         };
 
-        // Make sure the names match
-        // if the target is a locally defined symbol, then it might be the same
-        // todo(jon): hash the masked contents
-        if left_name != right_name {
-            return false;
-        }
+        // Skip over anything in the gap before this symbol
+        while relocations.next_if(|(offset, _)| *offset < start).is_some() {}
 
-        // Check the data
-        // the slice is the end of the relocation to the start of the previous relocation
-        let reloc_byte_size = (left_reloc.size() as usize) / 8;
-        let start = *l_addr as usize - left.offset as usize + reloc_byte_size;
+        // Walked in reverse everywhere else
+        let mut relocations = relocations
+            .peeking_take_while(|(offset, _)| *offset < end)
+            .collect::<Vec<_>>();
+        relocations.reverse();
 
-        // Some relocations target the same location
-        // In these cases, we just continue since we just masked and checked them already
-        if (*l_addr as usize - left.offset as usize) == last {
-            continue;
-        }
+        syms.push(RelocatedSymbol {
+            name: sym.name().unwrap(),
+            offset: start as usize,
+            size: (end - start) as usize,
+            data: data.get(start as usize..end as usize).unwrap_or_default(),
+            relocations,
+            sym,
+            section: section.index(),
+        });
+    }
 
-        debug_assert!(start <= last);
-        debug_assert!(start <= left.data.len());
+    syms
+}
 
-        if &left.data[start..last] != &right.data[start..last] {
-            return false;
-        }
+/// A relocated field within a symbol, normalized so fields from different objects can be compared
+#[derive(Debug, PartialEq, Eq)]
+struct RelocField<'a> {
+    /// Offset of the field from the start of the symbol
+    offset: usize,
+    width: usize,
+    target: Option<&'a str>,
 
-        if left_reloc.flags() != right_reloc.flags() {
-            return false;
+    /// None when the addend is encoded in an instruction we can't decode, or when it's relative
+    /// to a section and so shifts around whenever anything else in the section changes
+    addend: Option<i64>,
+    flags: RelocationFlags,
+}
+
+/// Collect the relocated fields of a symbol, sorted by offset
+fn reloc_fields<'a>(obj: &impl Object<'a>, sym: &RelocatedSymbol) -> Vec<RelocField<'a>> {
+    let arch = obj.architecture();
+
+    sym.relocations
+        .iter()
+        .map(|(addr, reloc)| {
+            let offset = *addr as usize - sym.offset;
+            let width = reloc_width(arch, reloc);
+
+            let addend = match reloc.target() {
+                RelocationTarget::Section(_) => None,
+                RelocationTarget::Symbol(idx)
+                    if obj
+                        .symbol_by_index(idx)
+                        .is_ok_and(|s| s.kind() == SymbolKind::Section) =>
+                {
+                    None
+                }
+                _ => effective_addend(arch, reloc, sym.data.get(offset..offset + width)),
+            };
+
+            RelocField {
+                offset,
+                width,
+                target: relo_target_name(obj, reloc.target()),
+                addend,
+                flags: reloc.flags(),
+            }
+        })
+        .sorted_by_key(|f| f.offset)
+        .collect()
+}
+
+/// How many bytes of the symbol a relocation overwrites
+fn reloc_width(arch: Architecture, reloc: &Relocation) -> usize {
+    use object::elf::*;
+
+    if let (Architecture::X86_64, RelocationFlags::Elf { r_type }) = (arch, reloc.flags()) {
+        match r_type {
+            R_X86_64_64 | R_X86_64_PC64 | R_X86_64_GOTOFF64 | R_X86_64_DTPOFF64
+            | R_X86_64_TPOFF64 => return 8,
+            R_X86_64_PC32
+            | R_X86_64_PLT32
+            | R_X86_64_GOTPCREL
+            | R_X86_64_GOTPCRELX
+            | R_X86_64_REX_GOTPCRELX
+            | R_X86_64_32
+            | R_X86_64_32S
+            | R_X86_64_GOTTPOFF
+            | R_X86_64_TPOFF32
+            | R_X86_64_TLSGD
+            | R_X86_64_TLSLD
+            | R_X86_64_DTPOFF32 => return 4,
+            _ => {}
         }
+    }
+
+    match (arch, reloc.size()) {
+        // Instruction relocations only cover some bits of the instruction. Mask the whole thing.
+        (Architecture::Aarch64, 0 | 12 | 14 | 16 | 19 | 21 | 26) => 4,
+        (_, 0) => 4,
+        (_, size) => size as usize / 8,
+    }
+}
 
-        // todo: more checking... the symbols might be local
-        last = start - reloc_byte_size;
+/// The addend of a relocation, reading it out of the field itself if the format stores it there
+fn effective_addend(arch: Architecture, reloc: &Relocation, field: Option<&[u8]>) -> Option<i64> {
+    if !reloc.has_implicit_addend() {
+        return Some(reloc.addend());
     }
 
-    // And a final check to make sure the data is the same
-    if left.data[..last] != right.data[..last] {
-        return false;
+    // AArch64 instruction immediates need decoding - leave them masked for now
+    if Aarch64Insn::of(arch, reloc).is_some() {
+        return None;
     }
 
-    true
+    let implicit = match field? {
+        [a, b, c, d] => i32::from_le_bytes([*a, *b, *c, *d]) as i64,
+        bytes @ [_, _, _, _, _, _, _, _] => i64::from_le_bytes(bytes.try_into().unwrap()),
+        _ => return None,
+    };
+
+    Some(implicit.wrapping_add(reloc.addend()))
+}
+
+/// The name of whatever a relocation points at, including sections
+fn relo_target_name<'a>(obj: &impl Object<'a>, target: RelocationTarget) -> Option<&'a str> {
+    match target {
+        RelocationTarget::Section(idx) => obj.section_by_index(idx).ok()?.name().ok(),
+        _ => symbol_name_of_relo(obj, target),
+    }
 }
 
 fn symbol_name_of_relo<'a>(obj: &impl Object<'a>, target: RelocationTarget) -> Option<&'a str> {
     match target {
-        RelocationTarget::Symbol(symbol_index) => Some(
-            obj.symbol_by_index(symbol_index)
-                .unwrap()
-                .name_bytes()
-                .unwrap()
-                .to_utf8(),
-        ),
+        RelocationTarget::Symbol(symbol_index) => {
+            let sym = obj.symbol_by_index(symbol_index).unwrap();
+
+            // ELF relocations often point at the section symbol which doesn't have a name
+            if sym.kind() == SymbolKind::Section {
+                return obj.section_by_index(sym.section_index()?).ok()?.name().ok();
+            }
+
+            Some(sym.name_bytes().unwrap().to_utf8())
+        }
         RelocationTarget::Section(_) => None,
         RelocationTarget::Absolute => None,
         _ => None,
     }
 }
 
+/// Local labels are only unique within a single object, so we tack the file name on
+fn local_name(sym: &str, file_name: &str) -> String {
+    match is_local_label(sym) {
+        true => format!("{sym}_{file_name}"),
+        false => sym.to_string(),
+    }
+}
+
 fn workspace_dir() -> PathBuf {
     "/Users/jonkelley/Development/Tinkering/ipbp".into()
 }
@@ -724,3 +1035,424 @@ fn build_stub(
 
     obj.write().context("Failed to write object file")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fingerprint::symbol_hash;
+    use super::*;
+    use crate::fixtures::TestObject;
+    use object::write::StandardSection;
+    use object::SymbolScope;
+    use sha2::{Digest, Sha256};
+
+    /// A function for the test objects: its name, body, and `(offset, target, addend)` PLT32 calls
+    pub(super) type TestFn<'a> = (&'a str, &'a [u8], &'a [(u64, &'a str, i64)]);
+
+    /// Build an x86_64 ELF object with one function section per function
+    pub(super) fn elf_with_functions(funcs: &[TestFn]) -> Vec<u8> {
+        let mut obj = TestObject::elf();
+
+        let mut symbols = HashMap::new();
+        for (name, body, _) in funcs {
+            symbols.insert(
+                name.to_string(),
+                obj.function(name, body, SymbolScope::Linkage),
+            );
+        }
+
+        for (name, _, relocs) in funcs {
+            let section = symbols[*name].1;
+            for (offset, target, addend) in relocs.iter() {
+                let symbol = match symbols.get(*target) {
+                    Some((id, _)) => *id,
+                    None => obj.import(target, false),
+                };
+                obj.relocate(
+                    section,
+                    *offset,
+                    symbol,
+                    *addend,
+                    object::elf::R_X86_64_PLT32,
+                );
+            }
+        }
+
+        obj.write()
+    }
+
+    fn function_units<'a>(file: &'a File<'a>) -> HashMap<&'a str, RelocatedSymbol<'a>> {
+        file.sections()
+            .filter(|s| s.kind() == SectionKind::Text)
+            .flat_map(|s| section_symbols(file, s.index()))
+            .map(|s| (s.name, s))
+            .collect()
+    }
+
+    fn same_fingerprint(
+        old: &File,
+        new: &File,
+        left: &RelocatedSymbol,
+        right: &RelocatedSymbol,
+    ) -> bool {
+        symbol_hash(old, &AnonIdentities::new(old), left)
+            == symbol_hash(new, &AnonIdentities::new(new), right)
+    }
+
+    /// Diff `foo` between two objects
+    fn foo_unchanged(old: &[TestFn], new: &[TestFn]) -> bool {
+        let old = elf_with_functions(old);
+        let new = elf_with_functions(new);
+        let old = File::parse(&*old).unwrap();
+        let new = File::parse(&*new).unwrap();
+        same_fingerprint(
+            &old,
+            &new,
+            &function_units(&old)["foo"],
+            &function_units(&new)["foo"],
+        )
+    }
+
+    #[test]
+    fn elf_function_sections_are_units() {
+        let old = elf_with_functions(&[("foo", &[0x90, 0x90, 0xc3], &[]), ("bar", &[0xc3], &[])]);
+        let new = elf_with_functions(&[
+            ("foo", &[0x90, 0x90, 0xc3], &[]),
+            ("bar", &[0x90, 0xc3], &[]),
+        ]);
+        let old = File::parse(&*old).unwrap();
+        let new = File::parse(&*new).unwrap();
+
+        let old_units = function_units(&old);
+        let new_units = function_units(&new);
+
+        // Functions without relocations still get their whole body
+        assert_eq!(new_units["foo"].data, &[0x90, 0x90, 0xc3]);
+        assert_eq!(new_units["bar"].data, &[0x90, 0xc3]);
+
+        assert!(same_fingerprint(
+            &old,
+            &new,
+            &old_units["foo"],
+            &new_units["foo"]
+        ));
+        assert!(!same_fingerprint(
+            &old,
+            &new,
+            &old_units["bar"],
+            &new_units["bar"]
+        ));
+    }
+
+    #[test]
+    fn x86_64_calls_compare_by_target_and_addend() {
+        // call rel32; ret
+        let call = [0xe8, 0, 0, 0, 0, 0xc3];
+
+        // The displacement bytes are masked out, only the target matters
+        let garbage = [0xe8, 0xde, 0xad, 0xbe, 0xef, 0xc3];
+        assert!(foo_unchanged(
+            &[("foo", &call, &[(1, "bar", -4)])],
+            &[("foo", &garbage, &[(1, "bar", -4)])],
+        ));
+
+        // Calling something else is a change
+        assert!(!foo_unchanged(
+            &[("foo", &call, &[(1, "bar", -4)])],
+            &[("foo", &call, &[(1, "baz", -4)])],
+        ));
+
+        // So is pointing somewhere else within the same target
+        assert!(!foo_unchanged(
+            &[("foo", &call, &[(1, "bar", -4)])],
+            &[("foo", &call, &[(1, "bar", 12)])],
+        ));
+
+        // The `ret` after the field is still compared
+        assert!(!foo_unchanged(
+            &[("foo", &call, &[(1, "bar", -4)])],
+            &[("foo", &[0xe8, 0, 0, 0, 0, 0x90], &[(1, "bar", -4)])],
+        ));
+    }
+
+    pub(super) fn table(files: &[(&str, &[(&str, u8)])]) -> FingerprintTable {
+        let files = files
+            .iter()
+            .map(|(name, syms)| {
+                let symbols = syms
+                    .iter()
+                    .map(|(sym, hash)| {
+                        let fingerprint = fingerprint::SymbolFingerprint {
+                            hash: [*hash; 32],
+                            class: SymbolClass::Function,
+                            exported: true,
+                            signature: None,
+                        };
+                        (sym.to_string(), fingerprint)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+
+                let file = FileFingerprints {
+                    content_hash: Sha256::digest(format!("{syms:?}")).into(),
+                    parents: BTreeMap::from([(
+                        syms[0].0.to_string(),
+                        BTreeSet::from(["main".to_string()]),
+                    )]),
+                    symbols,
+                    unresolved: vec![],
+                };
+                (name.to_string(), file)
+            })
+            .collect();
+
+        FingerprintTable { files }
+    }
+
+    #[test]
+    fn parallel_diff_merges_in_file_order() {
+        let old = table(&[
+            ("a.o", &[("a1", 0), ("a2", 0)]),
+            ("b.o", &[("b1", 0)]),
+            ("c.o", &[("c1", 0), ("gone", 0)]),
+        ]);
+        let new = table(&[
+            ("a.o", &[("a1", 1), ("a2", 0)]),
+            ("b.o", &[("b1", 0)]),
+            ("c.o", &[("c1", 1), ("new", 0)]),
+            ("d.o", &[("d1", 0)]),
+        ]);
+
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new.clone(), &old);
+        assert_eq!(
+            diff.modified_symbols.iter().collect_vec(),
+            ["a1", "c1", "d1", "new"]
+        );
+        assert_eq!(
+            diff.modified_files.keys().collect_vec(),
+            [
+                Path::new("objs/a.o"),
+                Path::new("objs/c.o"),
+                Path::new("objs/d.o")
+            ]
+        );
+        assert_eq!(diff.changes["gone"].kind, ChangeKind::Removed);
+        assert_eq!(diff.changes["new"].kind, ChangeKind::Added);
+        assert_eq!(diff.parents["a1"], BTreeSet::from(["main".to_string()]));
+
+        // Running it again gives exactly the same answer
+        let again = ObjectDiff::between(PathBuf::from("objs"), new, &old);
+        assert_eq!(diff.modified_symbols, again.modified_symbols);
+        assert_eq!(diff.modified_files, again.modified_files);
+        assert_eq!(diff.parents, again.parents);
+        assert_eq!(diff.changes, again.changes);
+        assert_eq!(diff.timings.pooling.items, 4);
+        assert_eq!(diff.timings.baseline.items, 3);
+        assert_eq!(diff.timings.diff.items, 6);
+    }
+
+    #[test]
+    fn symbols_moving_between_cgus_are_not_changes() {
+        let old = table(&[("a.o", &[("f1", 0), ("f2", 0)]), ("b.o", &[("g", 0)])]);
+
+        // `f2` moves into a brand new CGU along with an inlined copy of `g`
+        let new = table(&[
+            ("a.o", &[("f1", 0)]),
+            ("b.o", &[("g", 0)]),
+            ("c.o", &[("f2", 0), ("g", 0), ("h", 1)]),
+        ]);
+
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new, &old);
+        assert_eq!(diff.modified_symbols.iter().collect_vec(), ["h"]);
+        assert_eq!(diff.changes.len(), 1);
+
+        // Moving and changing is just a change, linked from wherever it lives now
+        let new = table(&[("a.o", &[("f1", 0)]), ("b.o", &[("g", 0), ("f2", 1)])]);
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new, &old);
+        assert_eq!(diff.changes["f2"].kind, ChangeKind::Modified);
+        assert_eq!(
+            diff.modified_files.keys().collect_vec(),
+            [Path::new("objs/b.o")]
+        );
+    }
+
+    #[test]
+    fn removed_functions_still_referenced_are_dangling() {
+        let mut old = table(&[("a.o", &[("helper", 0), ("tick", 0), ("other", 0)])]);
+        old.files.get_mut("a.o").unwrap().parents.insert(
+            "helper".to_string(),
+            BTreeSet::from(["tick".to_string(), "other".to_string()]),
+        );
+
+        // `other` was recompiled without `helper`, but `tick` wasn't
+        let new = table(&[("a.o", &[("tick", 0), ("other", 1)])]);
+
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new, &old);
+        assert_eq!(diff.changes["helper"].kind, ChangeKind::Removed);
+        assert_eq!(
+            diff.dangling,
+            [DanglingRef {
+                parent: "tick".to_string(),
+                target: "helper".to_string(),
+                reason: DanglingReason::Removed,
+            }]
+        );
+    }
+
+    #[test]
+    fn callers_of_changed_signatures_are_dangling() {
+        let signed = |table: &mut FingerprintTable, sym: &str, signature| {
+            let file = table.files.get_mut("a.o").unwrap();
+            file.symbols.get_mut(sym).unwrap().signature = Some([signature; 32]);
+        };
+
+        let mut old = table(&[("a.o", &[("helper", 0), ("tick", 0), ("other", 0)])]);
+        old.files.get_mut("a.o").unwrap().parents.insert(
+            "helper".to_string(),
+            BTreeSet::from(["tick".to_string(), "other".to_string()]),
+        );
+        signed(&mut old, "helper", 1);
+
+        // `helper` takes something new under the same name. `other` was recompiled for it, but
+        // `tick` wasn't.
+        let mut new = table(&[("a.o", &[("helper", 1), ("tick", 0), ("other", 1)])]);
+        signed(&mut new, "helper", 2);
+
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new.clone(), &old);
+        assert_eq!(
+            diff.dangling,
+            [DanglingRef {
+                parent: "tick".to_string(),
+                target: "helper".to_string(),
+                reason: DanglingReason::SignatureChanged,
+            }]
+        );
+
+        // A new body behind the same signature is fine
+        signed(&mut new, "helper", 1);
+        let diff = ObjectDiff::between(PathBuf::from("objs"), new, &old);
+        assert!(diff.dangling.is_empty());
+    }
+
+    /// Fingerprint every data symbol in an ELF object with the given constants and zero-fill statics
+    fn data_fingerprints(
+        consts: &[(&str, &[u8])],
+        bss: &[(&str, u64)],
+    ) -> HashMap<String, [u8; 32]> {
+        let mut obj = TestObject::elf();
+
+        let rodata = obj.section_id(StandardSection::ReadOnlyData);
+        let bss_section = obj.section_id(StandardSection::UninitializedData);
+
+        let placed = consts
+            .iter()
+            .map(|(name, bytes)| (*name, rodata, bytes.len() as u64, Some(*bytes)))
+            .chain(
+                bss.iter()
+                    .map(|(name, size)| (*name, bss_section, *size, None)),
+            );
+
+        for (name, section, size, bytes) in placed {
+            let value = match bytes {
+                Some(bytes) => obj.append_section_data(section, bytes, 1),
+                None => obj.append_section_bss(section, size, 1),
+            };
+            obj.define(
+                name,
+                section,
+                value,
+                size,
+                SymbolKind::Data,
+                SymbolScope::Linkage,
+            );
+        }
+
+        let data = obj.write();
+        let file = File::parse(&*data).unwrap();
+        let ids = AnonIdentities::new(&file);
+
+        file.sections()
+            .filter(|s| s.kind() != SectionKind::Text)
+            .flat_map(|s| section_symbols(&file, s.index()))
+            .map(|s| (s.name.to_string(), symbol_hash(&file, &ids, &s)))
+            .collect()
+    }
+
+    #[test]
+    fn data_symbols_compare_by_extent() {
+        let old = data_fingerprints(&[("GREETING", b"hello"), ("NAME", b"jon")], &[("COUNT", 8)]);
+
+        // No relocations anywhere, but the bytes of `NAME` changed
+        let new = data_fingerprints(&[("GREETING", b"hello"), ("NAME", b"bob")], &[("COUNT", 8)]);
+        assert_eq!(old["GREETING"], new["GREETING"]);
+        assert_ne!(old["NAME"], new["NAME"]);
+        assert_eq!(old["COUNT"], new["COUNT"]);
+
+        // Zero-fill statics have no bytes, so only their size can change
+        let new = data_fingerprints(
+            &[("GREETING", b"hello"), ("NAME", b"jon")],
+            &[("COUNT", 16)],
+        );
+        assert_ne!(old["COUNT"], new["COUNT"]);
+
+        assert_eq!(
+            SymbolClass::of_section(SectionKind::ReadOnlyString),
+            SymbolClass::Constant
+        );
+        assert_eq!(
+            SymbolClass::of_section(SectionKind::UninitializedData),
+            SymbolClass::Static
+        );
+    }
+
+    #[test]
+    fn renumbered_locals_keep_fingerprints() {
+        let call = [0xe8, 0, 0, 0, 0, 0xc3];
+        let literal: &[u8] = b"hello";
+
+        assert!(foo_unchanged(
+            &[
+                ("foo", &call, &[(1, "l_anon.1", -4)]),
+                ("l_anon.1", literal, &[])
+            ],
+            &[
+                ("foo", &call, &[(1, "l_anon.7", -4)]),
+                ("l_anon.7", literal, &[])
+            ],
+        ));
+
+        // But pointing at a different literal is still a change
+        assert!(!foo_unchanged(
+            &[
+                ("foo", &call, &[(1, "l_anon.1", -4)]),
+                ("l_anon.1", literal, &[])
+            ],
+            &[
+                ("foo", &call, &[(1, "l_anon.1", -4)]),
+                ("l_anon.1", b"world", &[])
+            ],
+        ));
+    }
+
+    #[test]
+    fn relocations_before_the_first_symbol_are_unresolved() {
+        let mut obj = TestObject::elf();
+        let text = obj.section_id(StandardSection::Text);
+
+        // A call in front of the only symbol in the section
+        obj.append_section_data(text, &[0xe8, 0, 0, 0, 0, 0xc3], 16);
+        let target = obj.import("callee", false);
+        obj.define("late", text, 5, 1, SymbolKind::Text, SymbolScope::Linkage);
+        obj.relocate(text, 1, target, -4, object::elf::R_X86_64_PLT32);
+
+        let loaded = LoadedFile::from_bytes(obj.write()).unwrap();
+        let graph = loaded.call_graph();
+        assert_eq!(
+            graph.unresolved,
+            [UnresolvedReloc {
+                symbol: ".text".to_string(),
+                offset: 1,
+                reason: "not covered by any symbol".to_string(),
+            }]
+        );
+        assert!(!graph.parents.contains_key("callee"));
+    }
+}
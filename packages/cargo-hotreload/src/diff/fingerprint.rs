@@ -0,0 +1,250 @@
+//! Relocation-normalized fingerprints for every symbol in a build
+//!
+//! Each symbol is hashed with its relocated fields masked out and replaced by what they point at
+//! (target name plus addend), so two symbols with the same fingerprint are the same code even if
+//! they were laid out differently. The table for each build is saved as a "generation", so a new
+//! build can be diffed against any earlier one without keeping its objects around, and objects
+//! that rustc reused verbatim don't need to be parsed again.
+
+use super::*;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FingerprintTable {
+    /// Object file name -> the symbols defined in it
+    pub files: BTreeMap<String, FileFingerprints>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileFingerprints {
+    /// Hash of the whole object. If this matches we can skip the file entirely.
+    pub content_hash: [u8; 32],
+    pub symbols: BTreeMap<String, SymbolFingerprint>,
+
+    /// symbol -> the symbols that reference it
+    pub parents: BTreeMap<String, BTreeSet<String>>,
+
+    /// Relocations whose target we couldn't work out
+    pub unresolved: Vec<UnresolvedReloc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolFingerprint {
+    pub hash: [u8; 32],
+    pub class: SymbolClass,
+    pub exported: bool,
+
+    /// Hash of a function's parameter and return types, when the object has the debuginfo to say
+    pub signature: Option<[u8; 32]>,
+}
+
+impl FingerprintTable {
+    /// Fingerprint every object in a directory, reusing entries from `cache` for objects whose
+    /// contents haven't changed
+    pub fn from_dir(dir: &Path, cache: &FingerprintTable) -> Result<Self> {
+        Ok(Self::from_dir_timed(dir, cache)?.0)
+    }
+
+    /// Objects are read, hashed and parsed in parallel since big crates can have hundreds of CGUs
+    pub fn from_dir_timed(dir: &Path, cache: &FingerprintTable) -> Result<(Self, PhaseTiming)> {
+        let cached = cache
+            .files
+            .values()
+            .map(|f| (f.content_hash, f))
+            .collect::<HashMap<_, _>>();
+
+        let paths = std::fs::read_dir(dir)?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension() == Some(OsStr::new("o")))
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let (files, timing) = PhaseTiming::par_map(&paths, |path| -> Result<_> {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let content_hash: [u8; 32] = Sha256::digest(std::fs::read(path)?).into();
+
+            let fingerprints = match cached.get(&content_hash) {
+                Some(f) => (*f).clone(),
+                None => FileFingerprints::new(path.clone(), content_hash)?,
+            };
+
+            Ok((name, fingerprints))
+        });
+
+        let files = files.into_iter().collect::<Result<_>>()?;
+        Ok((Self { files }, timing))
+    }
+
+    pub fn load(generation: usize) -> Result<Self> {
+        let data = std::fs::read(generation_path(generation))
+            .with_context(|| format!("No fingerprints for generation {generation}"))?;
+        Ok(bincode::deserialize(&data)?)
+    }
+
+    pub fn save(&self, generation: usize) -> Result<()> {
+        std::fs::create_dir_all(generations_dir())?;
+        std::fs::write(generation_path(generation), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Save the table of a patch build until we know whether the app loaded the patch. Until then
+    /// the next patch keeps diffing against the latest generation.
+    pub fn save_pending(&self) -> Result<()> {
+        std::fs::create_dir_all(generations_dir())?;
+        std::fs::write(pending_path(), bincode::serialize(self)?)?;
+        Ok(())
+    }
+}
+
+impl FileFingerprints {
+    fn new(path: PathBuf, content_hash: [u8; 32]) -> Result<Self> {
+        Ok(Self::of(&LoadedFile::open(path)?, content_hash))
+    }
+
+    /// Fingerprint an object that's already been loaded
+    pub(super) fn of(loaded: &LoadedFile, content_hash: [u8; 32]) -> Self {
+        let file = loaded.file();
+
+        let ids = AnonIdentities::new(file);
+        let signatures = signature::signatures(file).unwrap_or_else(|err| {
+            println!("Couldn't read function signatures: {err}");
+            HashMap::new()
+        });
+
+        let mut symbols = BTreeMap::new();
+        for section in file.sections().filter(should_diff) {
+            let class = SymbolClass::of_section(section.kind());
+            for sym in section_symbols(file, section.index()) {
+                let exported = sym.sym.is_global() && sym.sym.is_definition();
+                let hash = symbol_hash(file, &ids, &sym);
+
+                // Some names show up in more than one section - fold them together
+                let hash = match symbols.remove(sym.name) {
+                    Some(SymbolFingerprint { hash: prev, .. }) => Sha256::new()
+                        .chain_update(prev)
+                        .chain_update(hash)
+                        .finalize()
+                        .into(),
+                    None => hash,
+                };
+
+                symbols.insert(
+                    sym.name.to_string(),
+                    SymbolFingerprint {
+                        hash,
+                        class,
+                        exported,
+                        signature: signatures.get(sym.name).copied(),
+                    },
+                );
+            }
+        }
+
+        let graph = loaded.call_graph();
+        let parents = graph
+            .parents
+            .iter()
+            .map(|(child, parents)| {
+                (
+                    child.to_string(),
+                    parents.iter().map(|p| p.to_string()).collect(),
+                )
+            })
+            .collect();
+
+        Self {
+            content_hash,
+            symbols,
+            parents,
+            unresolved: graph.unresolved,
+        }
+    }
+}
+
+/// Hash a symbol's bytes with its relocated fields zeroed, plus what each of those fields points at
+///
+/// Anonymous targets are swapped for their stable identity so renumbering them isn't a change
+pub(super) fn symbol_hash<'a>(
+    obj: &impl Object<'a>,
+    ids: &AnonIdentities,
+    sym: &RelocatedSymbol,
+) -> [u8; 32] {
+    let fields = reloc_fields(obj, sym);
+
+    let mut masked = sym.data.to_vec();
+    for field in fields.iter() {
+        let end = (field.offset + field.width).min(masked.len());
+        if field.offset < end {
+            masked[field.offset..end].fill(0);
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update((sym.size as u64).to_le_bytes());
+    hasher.update(&masked);
+
+    for field in fields.iter() {
+        hasher.update((field.offset as u64).to_le_bytes());
+        hasher.update((field.width as u64).to_le_bytes());
+
+        let target = ids.stable(field.target.unwrap_or_default());
+        hasher.update((target.len() as u64).to_le_bytes());
+        hasher.update(target.as_bytes());
+
+        match field.addend {
+            Some(addend) => {
+                hasher.update([1]);
+                hasher.update(addend.to_le_bytes());
+            }
+            None => hasher.update([0]),
+        }
+
+        hasher.update(format!("{:?}", field.flags).as_bytes());
+    }
+
+    hasher.finalize().into()
+}
+
+/// The most recent generation saved, if any
+pub fn latest_generation() -> Option<usize> {
+    std::fs::read_dir(generations_dir())
+        .ok()?
+        .flatten()
+        .filter_map(|e| e.path().file_stem()?.to_str()?.parse().ok())
+        .max()
+}
+
+/// Make the pending table the next generation, once the app has loaded the patch built from it
+pub fn commit_pending_generation() -> Result<Option<usize>> {
+    if !pending_path().exists() {
+        return Ok(None);
+    }
+
+    let generation = latest_generation().map(|g| g + 1).unwrap_or_default();
+    std::fs::rename(pending_path(), generation_path(generation))?;
+    Ok(Some(generation))
+}
+
+/// Drop the pending table, since the app never loaded the patch built from it
+pub fn discard_pending_generation() {
+    _ = std::fs::remove_file(pending_path());
+}
+
+/// Throw away every generation - the next table saved becomes the new baseline
+pub fn clear_generations() {
+    _ = std::fs::remove_dir_all(generations_dir());
+}
+
+fn generations_dir() -> PathBuf {
+    workspace_dir().join("data").join("fingerprints")
+}
+
+fn generation_path(generation: usize) -> PathBuf {
+    generations_dir().join(format!("{generation}.bin"))
+}
+
+/// Not a number, so [`latest_generation`] never picks it up
+fn pending_path() -> PathBuf {
+    generations_dir().join("pending.bin")
+}
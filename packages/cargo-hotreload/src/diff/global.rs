@@ -0,0 +1,274 @@
+//! Every symbol of a build pooled together, regardless of which object it ended up in
+//!
+//! rustc repartitions CGUs as code changes, so a function can move from one object to another
+//! between builds without changing at all. Diffing object against object calls that an add and a
+//! remove, so instead we pool the symbols of every object and diff the pools.
+
+use super::fingerprint::SymbolFingerprint;
+use super::*;
+
+#[derive(Debug, Default)]
+pub(super) struct GlobalSymbols {
+    /// symbol -> object -> fingerprint. `#[inline]` functions get a copy in every CGU that uses them.
+    pub symbols: BTreeMap<String, BTreeMap<String, SymbolFingerprint>>,
+
+    /// symbol -> the symbols that reference it
+    pub parents: BTreeMap<String, BTreeSet<String>>,
+
+    pub unresolved: Vec<UnresolvedReloc>,
+}
+
+/// What a single object contributes. These are computed in parallel and then merged in file order
+/// so the result doesn't depend on which thread finished first.
+struct FileSymbols {
+    symbols: Vec<(String, SymbolFingerprint)>,
+    parents: Vec<(String, String)>,
+    unresolved: Vec<UnresolvedReloc>,
+}
+
+impl GlobalSymbols {
+    pub fn new(table: &FingerprintTable) -> (Self, PhaseTiming) {
+        let files = table.files.iter().collect::<Vec<_>>();
+        let (per_file, timing) =
+            PhaseTiming::par_map(&files, |(name, file)| FileSymbols::new(name, file));
+
+        let mut global = Self::default();
+        for ((name, _), file) in files.iter().zip(per_file) {
+            for (sym, fingerprint) in file.symbols {
+                global
+                    .symbols
+                    .entry(sym)
+                    .or_default()
+                    .insert(name.to_string(), fingerprint);
+            }
+
+            for (child, parent) in file.parents {
+                global.parents.entry(child).or_default().insert(parent);
+            }
+
+            global.unresolved.extend(file.unresolved);
+        }
+
+        (global, timing)
+    }
+
+    /// One fingerprint per anonymous symbol, for pairing them up between builds
+    fn anonymous(&self) -> BTreeMap<String, SymbolFingerprint> {
+        self.symbols
+            .iter()
+            .filter(|(name, _)| is_anonymous(name))
+            .filter_map(|(name, copies)| Some((name.clone(), copies.values().next()?.clone())))
+            .collect()
+    }
+
+    fn class(&self, name: &str) -> SymbolClass {
+        self.symbols[name].values().next().unwrap().class
+    }
+
+    fn signature(&self, name: &str) -> Option<[u8; 32]> {
+        self.symbols.get(name)?.values().find_map(|f| f.signature)
+    }
+}
+
+impl FileSymbols {
+    fn new(name: &str, file: &FileFingerprints) -> Self {
+        let symbols = file
+            .symbols
+            .iter()
+            .map(|(sym, fingerprint)| (local_name(sym, name), fingerprint.clone()))
+            .collect();
+
+        let parents = file
+            .parents
+            .iter()
+            .flat_map(|(child, parents)| {
+                let child = local_name(child, name);
+                parents
+                    .iter()
+                    .map(move |parent| (child.clone(), local_name(parent, name)))
+            })
+            .collect();
+
+        let unresolved = file
+            .unresolved
+            .iter()
+            .map(|u| UnresolvedReloc {
+                symbol: local_name(&u.symbol, name),
+                ..u.clone()
+            })
+            .collect();
+
+        Self {
+            symbols,
+            parents,
+            unresolved,
+        }
+    }
+}
+
+/// A changed symbol along with the objects of the new build that hold its new version
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct GlobalChange {
+    pub name: String,
+    pub change: SymbolChange,
+    pub files: Vec<String>,
+}
+
+/// Every change between two builds
+#[derive(Debug, Default)]
+pub(super) struct GlobalDiff {
+    pub changes: Vec<GlobalChange>,
+
+    /// Anonymous symbols that got renumbered: old name -> new name
+    pub renamed: BTreeMap<String, String>,
+}
+
+/// Something that survived into the new build unchanged but references a symbol that's gone or
+/// that no longer takes what it passes. The running process still has the old copy of it, which
+/// now calls into code that doesn't match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingRef {
+    pub parent: String,
+    pub target: String,
+    pub reason: DanglingReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DanglingReason {
+    /// The new build doesn't have the target anymore
+    Removed,
+
+    /// The target kept its name but its parameter or return types changed
+    SignatureChanged,
+}
+
+/// Diff two builds symbol by symbol
+pub(super) fn diff_symbols(old: &GlobalSymbols, new: &GlobalSymbols) -> GlobalDiff {
+    let mut changes = vec![];
+    let mut record = |name: &str, kind, class, files: Vec<&String>| {
+        changes.push(GlobalChange {
+            name: name.to_string(),
+            change: SymbolChange { kind, class },
+            files: files.into_iter().cloned().collect(),
+        })
+    };
+
+    // Named symbols line up by name, whichever object they're in
+    for (name, copies) in new.symbols.iter().filter(|(s, _)| !is_anonymous(s)) {
+        let class = new.class(name);
+        let Some(old_copies) = old.symbols.get(name) else {
+            record(name, ChangeKind::Added, class, copies.keys().collect());
+            continue;
+        };
+
+        // Any copy that doesn't match an old copy needs to be linked in
+        let old_hashes = old_copies.values().map(|f| f.hash).collect::<HashSet<_>>();
+        let changed = copies
+            .iter()
+            .filter(|(_, f)| !old_hashes.contains(&f.hash))
+            .map(|(file, _)| file)
+            .collect::<Vec<_>>();
+
+        if !changed.is_empty() {
+            record(name, ChangeKind::Modified, class, changed);
+        }
+    }
+
+    for name in old.symbols.keys().filter(|s| !is_anonymous(s)) {
+        if !new.symbols.contains_key(name) {
+            record(name, ChangeKind::Removed, old.class(name), vec![]);
+        }
+    }
+
+    // Anonymous ones get renumbered so they're matched up by content and by who uses them
+    let old_anon = old.anonymous();
+    let new_anon = new.anonymous();
+    let pairing = pair_anonymous(
+        &AnonSymbols::new(&old_anon, &old.parents),
+        &AnonSymbols::new(&new_anon, &new.parents),
+    );
+
+    let mut renamed = BTreeMap::new();
+    for (old_name, new_name) in pairing.paired {
+        if old_name != new_name {
+            renamed.insert(old_name.to_string(), new_name.to_string());
+        }
+
+        if old_anon[old_name].hash != new_anon[new_name].hash {
+            let files = new.symbols[new_name].keys().collect();
+            record(new_name, ChangeKind::Modified, new.class(new_name), files);
+        }
+    }
+
+    for name in pairing.added {
+        let files = new.symbols[name].keys().collect();
+        record(name, ChangeKind::Added, new.class(name), files);
+    }
+
+    for name in pairing.removed {
+        record(name, ChangeKind::Removed, old.class(name), vec![]);
+    }
+
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    GlobalDiff { changes, renamed }
+}
+
+/// Find the code left in the running process that still points at functions the new build removed,
+/// or at functions whose signature changed without their name changing.
+///
+/// Anything that referenced such a function in the old build had better be getting patched
+/// itself - otherwise it keeps calling the old version, which is stale at best if the function was
+/// inlined away, or calls the new one with the wrong arguments. Signatures are only known when the
+/// objects carry debuginfo.
+pub(super) fn dangling_references(
+    old: &GlobalSymbols,
+    new: &GlobalSymbols,
+    diff: &GlobalDiff,
+) -> Vec<DanglingRef> {
+    let removed = diff
+        .changes
+        .iter()
+        .filter(|c| c.change.kind == ChangeKind::Removed)
+        .map(|c| c.name.as_str())
+        .collect::<HashSet<_>>();
+
+    let patched = diff
+        .changes
+        .iter()
+        .filter(|c| c.change.kind != ChangeKind::Removed)
+        .map(|c| c.name.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut dangling = vec![];
+    for change in diff.changes.iter() {
+        if change.change.class != SymbolClass::Function {
+            continue;
+        }
+
+        let reason = match change.change.kind {
+            ChangeKind::Removed => DanglingReason::Removed,
+            ChangeKind::Modified => {
+                match (old.signature(&change.name), new.signature(&change.name)) {
+                    (Some(old), Some(new)) if old != new => DanglingReason::SignatureChanged,
+                    _ => continue,
+                }
+            }
+            ChangeKind::Added => continue,
+        };
+
+        for parent in old.parents.get(&change.name).into_iter().flatten() {
+            let new_name = diff.renamed.get(parent).unwrap_or(parent);
+            if removed.contains(parent.as_str()) || patched.contains(new_name.as_str()) {
+                continue;
+            }
+
+            dangling.push(DanglingRef {
+                parent: new_name.clone(),
+                target: change.name.clone(),
+                reason,
+            });
+        }
+    }
+
+    dangling
+}
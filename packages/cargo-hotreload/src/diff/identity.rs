@@ -0,0 +1,428 @@
+//! Stable names for symbols the compiler names for us
+//!
+//! Local labels (`ltmp0`, `l_anon.<hash>.12`, `.Lanon.<hash>.3`) and closures are numbered in the
+//! order rustc happens to emit them, so adding a string literal or a closure at the top of a file
+//! renames everything after it. Matching those by name makes every function that references them
+//! look modified. Instead we identify them by what they contain, what they point at, and who
+//! references them.
+
+use super::fingerprint::SymbolFingerprint;
+use super::*;
+use crate::symbol::SymbolName;
+use sha2::{Digest, Sha256};
+
+/// Symbols whose names are made up by the compiler and get renumbered between builds
+pub(super) fn is_anonymous(name: &str) -> bool {
+    is_local_label(name) || SymbolName::new(name).is_closure()
+}
+
+/// Assembler-local labels, which are only unique within their object. Anything else starting with
+/// an `l` is a real name, like `#[no_mangle] fn launch` or an import of `lseek`.
+pub(super) fn is_local_label(name: &str) -> bool {
+    ["l_", "ltmp", ".L", "anon."]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// A stand-in name for every anonymous symbol in an object, used in place of the real name when
+/// something references it
+pub(super) struct AnonIdentities<'a> {
+    ids: HashMap<&'a str, String>,
+}
+
+impl<'a> AnonIdentities<'a> {
+    pub fn new(file: &'a File<'a>) -> Self {
+        let arch = file.architecture();
+
+        // section -> address -> symbols defined there
+        let mut by_address = HashMap::<SectionIndex, BTreeMap<u64, Vec<&'a str>>>::new();
+        for sym in file.symbols().filter(|s| s.is_definition()) {
+            if let (Some(section), Ok(name)) = (sym.section_index(), sym.name()) {
+                by_address
+                    .entry(section)
+                    .or_default()
+                    .entry(sym.address())
+                    .or_default()
+                    .push(name);
+            }
+        }
+
+        // section -> offset -> each relocated field, gathered once per section
+        let mut fields = HashMap::<SectionIndex, BTreeMap<u64, AnonField<'a>>>::new();
+
+        let mut ids = HashMap::new();
+        let mut contents = Vec::new();
+        for sym in file.symbols().filter(|s| s.is_definition()) {
+            let (Some(section_idx), Ok(name)) = (sym.section_index(), sym.name()) else {
+                continue;
+            };
+
+            if !is_anonymous(name) {
+                continue;
+            }
+
+            let defs = &by_address[&section_idx];
+
+            // An alias of a real symbol, like `ltmp0` sitting on top of the first function
+            if let Some(named) = defs[&sym.address()].iter().find(|n| !is_anonymous(n)) {
+                ids.insert(name, format!("<alias of {named}>"));
+                continue;
+            }
+
+            let Ok(section) = file.section_by_index(section_idx) else {
+                continue;
+            };
+
+            // Everything up to the next symbol belongs to this one
+            let start = sym.address() - section.address();
+            let end = match sym.size() {
+                0 => defs
+                    .range(sym.address() + 1..)
+                    .next()
+                    .map(|(addr, _)| addr - section.address())
+                    .unwrap_or(section.size()),
+                size => start + size,
+            };
+
+            let mut bytes = section
+                .data()
+                .ok()
+                .and_then(|d| d.get(start as usize..end as usize))
+                .unwrap_or_default()
+                .to_vec();
+
+            // Pointers inside the data shift around with the layout, so leave them out
+            let fields = fields.entry(section_idx).or_insert_with(|| {
+                section
+                    .relocations()
+                    .map(|(offset, reloc)| (offset, AnonField::new(file, arch, &reloc)))
+                    .collect()
+            });
+            let mut targets = Vec::new();
+            for (offset, field) in fields.range(start..end) {
+                let from = (offset - start) as usize;
+                let to = (from + field.width).min(bytes.len());
+                bytes[from..to].fill(0);
+                targets.push((offset - start, *field));
+            }
+
+            let hash = Sha256::new()
+                .chain_update((end - start).to_le_bytes())
+                .chain_update(&bytes)
+                .finalize();
+            contents.push((name, section.kind(), hash, targets));
+        }
+
+        // What the pointers point at still tells apart data that only differs in them. Anonymous
+        // targets go by their bytes alone, so symbols pointing at each other don't go in circles.
+        let by_bytes = contents
+            .iter()
+            .map(|(name, kind, hash, _)| (*name, format!("<{kind:?} {}>", short_hash(hash))))
+            .collect::<HashMap<_, _>>();
+        for (name, kind, hash, targets) in contents {
+            let mut hasher = Sha256::new().chain_update(hash);
+            for (offset, field) in targets {
+                let target = field.target.unwrap_or_default();
+                let target = by_bytes
+                    .get(target)
+                    .or(ids.get(target))
+                    .map(|id| id.as_str())
+                    .unwrap_or(target);
+                hasher.update(offset.to_le_bytes());
+                hasher.update((target.len() as u64).to_le_bytes());
+                hasher.update(target.as_bytes());
+                hasher.update(field.addend.unwrap_or_default().to_le_bytes());
+            }
+
+            let hash = short_hash(&hasher.finalize());
+            ids.insert(name, format!("<{kind:?} {hash}>"));
+        }
+
+        Self { ids }
+    }
+
+    /// The name to use for a symbol when hashing whatever references it
+    pub fn stable<'b>(&'b self, name: &'b str) -> &'b str {
+        self.ids.get(name).map(|s| s.as_str()).unwrap_or(name)
+    }
+}
+
+/// A relocated field inside an anonymous symbol
+#[derive(Clone, Copy)]
+struct AnonField<'a> {
+    width: usize,
+    target: Option<&'a str>,
+
+    /// Left out for section targets, since where in the section they land moves with the layout
+    addend: Option<i64>,
+}
+
+impl<'a> AnonField<'a> {
+    fn new(file: &'a File<'a>, arch: Architecture, reloc: &Relocation) -> Self {
+        let section_target = match reloc.target() {
+            RelocationTarget::Section(_) => true,
+            RelocationTarget::Symbol(idx) => file
+                .symbol_by_index(idx)
+                .is_ok_and(|s| s.kind() == SymbolKind::Section),
+            _ => false,
+        };
+
+        Self {
+            width: reloc_width(arch, reloc),
+            target: relo_target_name(file, reloc.target()),
+            addend: (!section_target).then(|| reloc.addend()),
+        }
+    }
+}
+
+fn short_hash(hash: &[u8]) -> String {
+    hash[..8].iter().map(|b| format!("{b:02x}")).join("")
+}
+
+/// The anonymous symbols of an object and who references them
+pub(super) struct AnonSymbols<'a> {
+    symbols: &'a BTreeMap<String, SymbolFingerprint>,
+    parents: &'a BTreeMap<String, BTreeSet<String>>,
+}
+
+impl<'a> AnonSymbols<'a> {
+    pub fn new(
+        symbols: &'a BTreeMap<String, SymbolFingerprint>,
+        parents: &'a BTreeMap<String, BTreeSet<String>>,
+    ) -> Self {
+        Self { symbols, parents }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &'a String> {
+        self.symbols.keys().filter(|name| is_anonymous(name))
+    }
+
+    /// The named functions that reference a symbol
+    fn referrers(&self, name: &str) -> Option<Vec<&'a str>> {
+        let referrers = self
+            .parents
+            .get(name)?
+            .iter()
+            .filter(|p| !is_anonymous(p))
+            .map(|p| p.as_str())
+            .collect::<Vec<_>>();
+
+        (!referrers.is_empty()).then_some(referrers)
+    }
+}
+
+/// How the anonymous symbols of two builds of an object line up
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct AnonPairing<'a> {
+    /// old name -> new name
+    pub paired: Vec<(&'a str, &'a str)>,
+    pub added: Vec<&'a str>,
+    pub removed: Vec<&'a str>,
+}
+
+/// Pair up anonymous symbols, first by content, then by the functions that reference them, and
+/// finally by name. Whatever is left over was added or removed.
+///
+/// Names come last since renumbering hands an old name to a different symbol.
+pub(super) fn pair_anonymous<'a>(old: &AnonSymbols<'a>, new: &AnonSymbols<'a>) -> AnonPairing<'a> {
+    let mut pairing = AnonPairing::default();
+    let mut old_left = old.names().map(|n| n.as_str()).collect::<BTreeSet<_>>();
+    let mut new_left = new.names().map(|n| n.as_str()).collect::<Vec<_>>();
+
+    // Identical contents - renumbered but otherwise untouched
+    let mut by_hash = HashMap::<_, Vec<&str>>::new();
+    for name in old_left.iter().rev() {
+        by_hash
+            .entry(old.symbols[*name].hash)
+            .or_default()
+            .push(*name);
+    }
+    new_left.retain(|name| {
+        let Some(old_name) = by_hash
+            .get_mut(&new.symbols[*name].hash)
+            .and_then(|names| names.pop())
+        else {
+            return true;
+        };
+
+        old_left.remove(old_name);
+        pairing.paired.push((old_name, name));
+        false
+    });
+
+    // Referenced from the same functions - a literal or closure that was edited in place
+    let mut by_referrers = HashMap::<_, Vec<&str>>::new();
+    for name in old_left.iter().rev() {
+        if let Some(referrers) = old.referrers(name) {
+            let key = (referrers, old.symbols[*name].class);
+            by_referrers.entry(key).or_default().push(*name);
+        }
+    }
+    new_left.retain(|name| {
+        let Some(referrers) = new.referrers(name) else {
+            return true;
+        };
+
+        let key = (referrers, new.symbols[*name].class);
+        let Some(old_name) = by_referrers.get_mut(&key).and_then(|names| names.pop()) else {
+            return true;
+        };
+
+        old_left.remove(old_name);
+        pairing.paired.push((old_name, name));
+        false
+    });
+
+    // Same name - most likely the same symbol with new contents
+    new_left.retain(|name| match old_left.remove(name) {
+        true => {
+            pairing.paired.push((name, name));
+            false
+        }
+        false => true,
+    });
+
+    pairing.added = new_left;
+    pairing.removed = old_left.into_iter().collect();
+    pairing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(name, hash, referrer)` for each symbol
+    fn object(syms: &[(&str, u8, &str)]) -> FileFingerprints {
+        FileFingerprints {
+            content_hash: [0; 32],
+            symbols: syms
+                .iter()
+                .map(|(name, hash, _)| {
+                    let fingerprint = SymbolFingerprint {
+                        hash: [*hash; 32],
+                        class: SymbolClass::Static,
+                        exported: false,
+                        signature: None,
+                    };
+                    (name.to_string(), fingerprint)
+                })
+                .collect(),
+            parents: syms
+                .iter()
+                .map(|(name, _, referrer)| {
+                    (name.to_string(), BTreeSet::from([referrer.to_string()]))
+                })
+                .collect(),
+            unresolved: vec![],
+        }
+    }
+
+    #[test]
+    fn pairs_renumbered_literals() {
+        let old = object(&[
+            ("l_anon.1", 1, "foo"),
+            ("l_anon.2", 2, "bar"),
+            ("l_anon.3", 3, "baz"),
+        ]);
+
+        // A new literal at the top of the file pushes everything down one
+        let new = object(&[
+            ("l_anon.1", 9, "qux"),
+            ("l_anon.2", 1, "foo"),
+            ("l_anon.3", 2, "bar"),
+            ("l_anon.4", 4, "baz"),
+        ]);
+
+        let pairing = pair_anonymous(
+            &AnonSymbols::new(&old.symbols, &old.parents),
+            &AnonSymbols::new(&new.symbols, &new.parents),
+        );
+
+        assert_eq!(
+            pairing,
+            AnonPairing {
+                paired: vec![
+                    ("l_anon.1", "l_anon.2"),
+                    ("l_anon.2", "l_anon.3"),
+                    ("l_anon.3", "l_anon.4"),
+                ],
+                added: vec!["l_anon.1"],
+                removed: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn pairs_edited_literals_that_were_renumbered() {
+        let old = object(&[("l_anon.1", 1, "foo"), ("l_anon.2", 2, "bar")]);
+
+        // `foo`'s literal was edited and pushed down by a new one for `qux`
+        let new = object(&[
+            ("l_anon.1", 9, "qux"),
+            ("l_anon.2", 5, "foo"),
+            ("l_anon.3", 2, "bar"),
+        ]);
+
+        let pairing = pair_anonymous(
+            &AnonSymbols::new(&old.symbols, &old.parents),
+            &AnonSymbols::new(&new.symbols, &new.parents),
+        );
+
+        assert_eq!(
+            pairing,
+            AnonPairing {
+                paired: vec![("l_anon.2", "l_anon.3"), ("l_anon.1", "l_anon.2")],
+                added: vec!["l_anon.1"],
+                removed: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn only_compiler_labels_are_anonymous() {
+        for label in ["ltmp0", "l_anon.5f2a.12", ".Lanon.5f2a.3", "anon.5f2a.0"] {
+            assert!(is_anonymous(label), "{label}");
+        }
+        for name in [
+            "launch",
+            "load",
+            "lseek",
+            "_ZN7harness4load17h5c3f0a9e1d2b4c6aE",
+        ] {
+            assert!(!is_anonymous(name), "{name}");
+        }
+    }
+
+    /// Vtables and the like are all zeroes once their pointers are masked, so what the pointers
+    /// point at has to tell them apart
+    #[test]
+    fn identities_follow_relocation_targets() {
+        let mut obj = crate::fixtures::TestObject::elf();
+        let (first, _) = obj.function("first", &[0xc3], object::SymbolScope::Linkage);
+        let (second, _) = obj.function("second", &[0xc3], object::SymbolScope::Linkage);
+
+        let rodata = obj.section_id(object::write::StandardSection::ReadOnlyData);
+        for (name, target) in [
+            ("anon.5f2a.0", first),
+            ("anon.5f2a.1", second),
+            ("anon.5f2a.2", first),
+        ] {
+            let offset = obj.append_section_data(rodata, &[0; 8], 8);
+            obj.define(
+                name,
+                rodata,
+                offset,
+                8,
+                SymbolKind::Data,
+                object::SymbolScope::Compilation,
+            );
+            obj.relocate(rodata, offset, target, 0, object::elf::R_X86_64_64);
+        }
+
+        let data = obj.write();
+        let file = File::parse(&*data).unwrap();
+        let ids = AnonIdentities::new(&file);
+        assert_ne!(ids.stable("anon.5f2a.0"), ids.stable("anon.5f2a.1"));
+        assert_eq!(ids.stable("anon.5f2a.0"), ids.stable("anon.5f2a.2"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Which hot entry points a change reaches
+//!
+//! A patch only shows up once something calls back into the code it replaced. For UI code that's
+//! the component wrapped by `#[binary_patch::start]`, so for each modified symbol we walk up the
+//! reverse call graph to the entry points that lead to it.
+
+use super::*;
+use crate::symbol::{demangle, is_main};
+use std::collections::VecDeque;
+
+/// The `#[no_mangle]` inner functions `#[binary_patch::start]` generates start with this
+const HOT_ENTRY_PREFIX: &str = "hotreload_start_";
+
+/// Whether the runtime can re-run a symbol to pick up new code: `main`, or a function registered
+/// through `#[binary_patch::start]`
+pub fn is_entry_point(name: &str) -> bool {
+    // Mach-O puts an extra underscore in front of everything
+    is_main(name) || name.trim_start_matches('_').starts_with(HOT_ENTRY_PREFIX)
+}
+
+/// The name a hot function was registered under with `use_hotreload_component`
+pub fn hot_function_name(symbol: &str) -> Option<String> {
+    let trimmed = symbol.trim_start_matches('_');
+    trimmed
+        .starts_with(HOT_ENTRY_PREFIX)
+        .then(|| format!("__{trimmed}"))
+}
+
+/// The reverse call graph of a build, for asking what a change affects
+pub(super) struct Impact<'a> {
+    parents: &'a BTreeMap<String, BTreeSet<String>>,
+}
+
+impl<'a> Impact<'a> {
+    pub fn new(parents: &'a BTreeMap<String, BTreeSet<String>>) -> Self {
+        Self { parents }
+    }
+
+    /// Every modified symbol along with the entry points that lead to it
+    pub fn affected<'b>(
+        &self,
+        modified: impl IntoIterator<Item = &'b str>,
+    ) -> BTreeMap<String, BTreeSet<String>> {
+        modified
+            .into_iter()
+            .map(|name| (name.to_string(), self.subgraph([name]).entry_points()))
+            .collect()
+    }
+
+    /// The registered hot functions the runtime needs to re-run to pick up the modified symbols.
+    /// `main` doesn't count - it's already running and can't be re-entered.
+    pub fn hot_functions<'b>(&self, modified: impl IntoIterator<Item = &'b str>) -> Vec<String> {
+        self.subgraph(modified)
+            .entry_points()
+            .iter()
+            .filter_map(|name| hot_function_name(name))
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    /// Everything between the modified symbols and the entry points above them.
+    ///
+    /// The walk stops at the first entry point on each path - that's where the runtime picks the
+    /// new code back up, so whatever calls it isn't affected.
+    pub fn subgraph<'b>(&self, modified: impl IntoIterator<Item = &'b str>) -> ImpactGraph {
+        let modified = modified.into_iter().collect::<BTreeSet<_>>();
+        let mut visited = modified.clone();
+        let mut queue = modified.iter().copied().collect::<VecDeque<_>>();
+        let mut edges = BTreeSet::new();
+
+        while let Some(name) = queue.pop_front() {
+            if is_entry_point(name) {
+                continue;
+            }
+
+            for parent in self.parents.get(name).into_iter().flatten() {
+                edges.insert((parent.as_str(), name));
+                if visited.insert(parent.as_str()) {
+                    queue.push_back(parent);
+                }
+            }
+        }
+
+        let nodes = visited
+            .iter()
+            .map(|name| GraphNode {
+                name: name.to_string(),
+                demangled: demangle(name),
+                kind: match name {
+                    name if is_entry_point(name) => NodeKind::EntryPoint,
+                    name if modified.contains(name) => NodeKind::Modified,
+                    _ => NodeKind::Caller,
+                },
+            })
+            .collect();
+
+        let edges = edges
+            .into_iter()
+            .map(|(caller, callee)| GraphEdge {
+                caller: caller.to_string(),
+                callee: callee.to_string(),
+            })
+            .collect();
+
+        ImpactGraph { nodes, edges }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Modified,
+    Caller,
+    EntryPoint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphNode {
+    pub name: String,
+    pub demangled: String,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphEdge {
+    pub caller: String,
+    pub callee: String,
+}
+
+/// The part of the call graph a change ripples through, in name order
+#[derive(Debug, Default, Serialize)]
+pub struct ImpactGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl ImpactGraph {
+    pub fn entry_points(&self) -> BTreeSet<String> {
+        self.nodes
+            .iter()
+            .filter(|n| n.kind == NodeKind::EntryPoint)
+            .map(|n| n.name.clone())
+            .collect()
+    }
+
+    /// Render as Graphviz, callers pointing at callees
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph impact {\n    node [shape=box, fontname=monospace];\n");
+
+        for node in self.nodes.iter() {
+            let style = match node.kind {
+                NodeKind::Modified => ", style=filled, fillcolor=salmon",
+                NodeKind::EntryPoint => ", style=filled, fillcolor=palegreen",
+                NodeKind::Caller => "",
+            };
+            dot.push_str(&format!(
+                "    {:?} [label={:?}{style}];\n",
+                node.name, node.demangled
+            ));
+        }
+
+        for edge in self.edges.iter() {
+            dot.push_str(&format!("    {:?} -> {:?};\n", edge.caller, edge.callee));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(child, parent)` edges
+    fn parents(edges: &[(&str, &str)]) -> BTreeMap<String, BTreeSet<String>> {
+        let mut parents = BTreeMap::<String, BTreeSet<String>>::new();
+        for (child, parent) in edges {
+            parents
+                .entry(child.to_string())
+                .or_default()
+                .insert(parent.to_string());
+        }
+        parents
+    }
+
+    #[test]
+    fn finds_nearest_entry_points() {
+        let parents = parents(&[
+            ("button", "toolbar"),
+            ("toolbar", "__hotreload_start_app"),
+            ("toolbar", "__hotreload_start_settings"),
+            ("__hotreload_start_app", "app"),
+            ("app", "main"),
+            ("logger", "main"),
+            ("orphan", "nobody"),
+        ]);
+        let impact = Impact::new(&parents);
+
+        let affected = impact.affected(["button", "logger", "orphan"]);
+        let entries = |name: &str| affected[name].iter().map(|e| e.as_str()).collect_vec();
+        assert_eq!(
+            entries("button"),
+            ["__hotreload_start_app", "__hotreload_start_settings"]
+        );
+        assert_eq!(entries("logger"), ["main"]);
+        assert!(entries("orphan").is_empty());
+
+        // The walk doesn't go past the component into `app` and `main`
+        let graph = impact.subgraph(["button"]);
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.nodes[2].kind, NodeKind::Modified);
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("\"toolbar\" -> \"button\";"));
+        assert!(!dot.contains("\"app\""));
+    }
+
+    #[test]
+    fn hot_functions_use_registered_names() {
+        let parents = parents(&[("button", "___hotreload_start_app"), ("logger", "_main")]);
+        let impact = Impact::new(&parents);
+
+        // Mach-O's extra underscore is dropped, and `main` can't be re-run
+        assert_eq!(
+            impact.hot_functions(["button", "logger"]),
+            ["__hotreload_start_app"]
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! `cargo hotreload diff` - compare two snapshots of a build outside the reload loop
+//!
+//! When an edit produces a much bigger patch than expected, this lists every symbol the diff engine
+//! thinks changed along with what actually differs: which bytes, and which relocations.
+
+use super::*;
+use crate::symbol::{demangle, SymbolFlavor, SymbolName};
+use object::read::archive::ArchiveFile;
+use sha2::{Digest, Sha256};
+
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    /// The old build: a directory of objects, a single object, or an rlib
+    #[arg(required_unless_present = "against")]
+    old: Option<PathBuf>,
+
+    /// The new build, in any of the same forms
+    #[arg(required_unless_present = "against")]
+    new: Option<PathBuf>,
+
+    /// Instead of two builds on disk, diff the last build the linker saw against a generation
+    /// saved by the reload loop. 0 is the build the app was launched from.
+    #[arg(long, value_name = "GENERATION", conflicts_with_all = ["old", "new"])]
+    against: Option<usize>,
+
+    /// Print the diff as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Instead of the symbols, print the part of the new build's call graph between the changes
+    /// and the entry points they affect
+    #[arg(long, value_enum)]
+    call_graph: Option<GraphFormat>,
+
+    /// Write the call graph to a file, like `impact.dot`, instead of stdout
+    #[arg(long, short, requires = "call_graph")]
+    output: Option<PathBuf>,
+
+    /// Keep the hash suffix on demangled names
+    #[arg(long)]
+    hashes: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl DiffArgs {
+    pub fn run(&self) -> Result<()> {
+        let SnapshotDiff {
+            symbols: diffs,
+            parents,
+        } = match (self.against, &self.old, &self.new) {
+            (Some(generation), ..) => ObjectDiff::against(generation)?.into(),
+            (None, Some(old), Some(new)) => {
+                diff_snapshots(&Snapshot::open(old)?, &Snapshot::open(new)?)
+            }
+            _ => anyhow::bail!("Need either two builds or --against"),
+        };
+
+        if let Some(format) = self.call_graph {
+            let changed = diffs
+                .iter()
+                .filter(|d| d.kind != ChangeKind::Removed)
+                .map(|d| d.name.as_str());
+            let graph = Impact::new(&parents).subgraph(changed);
+
+            let rendered = match format {
+                GraphFormat::Dot => graph.to_dot(),
+                GraphFormat::Json => format!("{}\n", serde_json::to_string_pretty(&graph)?),
+            };
+            match &self.output {
+                Some(path) => fs::write(path, rendered)
+                    .with_context(|| format!("Failed to write the call graph to {path:?}"))?,
+                None => print!("{rendered}"),
+            }
+            return Ok(());
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&diffs)?);
+            return Ok(());
+        }
+
+        for diff in diffs.iter() {
+            diff.print(self.hashes);
+        }
+
+        let count = |kind| diffs.iter().filter(|d| d.kind == kind).count();
+        println!(
+            "{} modified, {} added, {} removed",
+            count(ChangeKind::Modified),
+            count(ChangeKind::Added),
+            count(ChangeKind::Removed)
+        );
+
+        Ok(())
+    }
+}
+
+/// The objects making up one side of the diff, by file name
+struct Snapshot {
+    objects: BTreeMap<String, LoadedFile>,
+}
+
+impl Snapshot {
+    fn open(path: &Path) -> Result<Self> {
+        let mut objects = BTreeMap::new();
+
+        if path.is_dir() {
+            for path in fs::read_dir(path)?.flatten().map(|entry| entry.path()) {
+                if path.extension() == Some(OsStr::new("o")) {
+                    objects.insert(file_name(&path), LoadedFile::open(path)?);
+                }
+            }
+        } else if path.extension() == Some(OsStr::new("rlib")) {
+            let data = fs::read(path)?;
+            let archive =
+                ArchiveFile::parse(&*data).with_context(|| format!("Failed to parse {path:?}"))?;
+
+            // rlibs also carry the crate metadata, which isn't an object
+            for member in archive.members() {
+                let member = member?;
+                let name = String::from_utf8_lossy(member.name()).to_string();
+                if name.ends_with(".o") {
+                    let bytes = member.data(&*data)?.to_vec();
+                    let loaded = LoadedFile::from_bytes(bytes)
+                        .with_context(|| format!("Failed to parse {name} in {path:?}"))?;
+                    objects.insert(name, loaded);
+                }
+            }
+        } else {
+            objects.insert(file_name(path), LoadedFile::open(path.to_path_buf())?);
+        }
+
+        anyhow::ensure!(!objects.is_empty(), "No objects found in {path:?}");
+        Ok(Self { objects })
+    }
+
+    fn fingerprints(&self) -> FingerprintTable {
+        let objects = self.objects.iter().collect::<Vec<_>>();
+        let (files, _) = PhaseTiming::par_map(&objects, |(name, loaded)| {
+            let content_hash = Sha256::digest(loaded.borrow_data().deref()).into();
+            (name.to_string(), FileFingerprints::of(loaded, content_hash))
+        });
+
+        FingerprintTable {
+            files: files.into_iter().collect(),
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Everything that differs about one symbol between the two builds
+#[derive(Debug, Serialize)]
+struct SymbolDiff {
+    name: String,
+    demangled: String,
+    flavor: SymbolFlavor,
+    kind: ChangeKind,
+    class: SymbolClass,
+
+    /// The object holding the symbol in each build
+    old_object: Option<String>,
+    new_object: Option<String>,
+
+    /// Only when the symbol is in both builds
+    bytes: Option<ByteDiff>,
+
+    /// Targets the new version references that the old one doesn't, and vice versa
+    relocations_added: Vec<String>,
+    relocations_removed: Vec<String>,
+}
+
+/// How the bytes of a symbol differ once the relocated fields are masked out
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct ByteDiff {
+    old_size: usize,
+    new_size: usize,
+
+    /// Bytes that differ, counting any the longer version has past the end of the shorter one
+    differing: usize,
+    first_difference: Option<usize>,
+}
+
+impl ByteDiff {
+    fn new(old: &[u8], new: &[u8]) -> Self {
+        let mismatched = old.iter().zip(new).positions(|(a, b)| a != b).collect_vec();
+        let overhang = old.len().abs_diff(new.len());
+
+        Self {
+            old_size: old.len(),
+            new_size: new.len(),
+            differing: mismatched.len() + overhang,
+            first_difference: mismatched
+                .first()
+                .copied()
+                .or((overhang > 0).then(|| old.len().min(new.len()))),
+        }
+    }
+}
+
+impl SymbolDiff {
+    fn print(&self, hashes: bool) {
+        let sigil = match self.kind {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Modified => "~",
+        };
+
+        let name = match hashes {
+            true => SymbolName::new(&self.name).with_hash(),
+            false => self.demangled.clone(),
+        };
+
+        let object = self.new_object.as_ref().or(self.old_object.as_ref());
+        println!(
+            "{sigil} {:?} {name} ({})",
+            self.class,
+            object.map(|o| o.as_str()).unwrap_or("?")
+        );
+
+        if let Some(bytes) = &self.bytes {
+            match bytes.first_difference {
+                Some(first) => println!(
+                    "    {} -> {} bytes, {} differ starting at +{first:#x}",
+                    bytes.old_size, bytes.new_size, bytes.differing
+                ),
+                None => println!("    {} bytes, identical", bytes.new_size),
+            }
+        }
+
+        for target in self.relocations_added.iter() {
+            println!("    + {target}");
+        }
+        for target in self.relocations_removed.iter() {
+            println!("    - {target}");
+        }
+    }
+}
+
+/// One side of a symbol: its masked bytes and what its relocations point at
+struct SymbolContents {
+    object: String,
+    masked: Vec<u8>,
+    targets: Vec<String>,
+}
+
+impl SymbolContents {
+    /// Look up a symbol by its pooled name in one of the objects holding it
+    fn find(snapshot: &Snapshot, object: &str, name: &str) -> Option<Self> {
+        let file = snapshot.objects.get(object)?.file();
+        let name = match is_local_label(name) {
+            true => name.strip_suffix(&format!("_{object}")).unwrap_or(name),
+            false => name,
+        };
+        let ids = AnonIdentities::new(file);
+
+        let sym = file
+            .sections()
+            .filter(should_diff)
+            .flat_map(|section| section_symbols(file, section.index()))
+            .find(|sym| sym.name == name)?;
+
+        let fields = reloc_fields(file, &sym);
+        let mut masked = sym.data.to_vec();
+        for field in fields.iter() {
+            let end = (field.offset + field.width).min(masked.len());
+            masked[field.offset.min(end)..end].fill(0);
+        }
+
+        let targets = fields
+            .iter()
+            .map(|field| {
+                let target = field.target.map(|t| ids.stable(t)).unwrap_or("?");
+                match field.addend {
+                    Some(addend) if addend != 0 => format!("{}{addend:+}", demangle(target)),
+                    _ => demangle(target),
+                }
+            })
+            .sorted()
+            .collect();
+
+        Some(Self {
+            object: object.to_string(),
+            masked,
+            targets,
+        })
+    }
+}
+
+struct SnapshotDiff {
+    /// In name order
+    symbols: Vec<SymbolDiff>,
+
+    /// The reverse call graph of the new snapshot
+    parents: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Diff two snapshots symbol by symbol
+fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> SnapshotDiff {
+    let old_table = old.fingerprints();
+    let new_table = new.fingerprints();
+    let (old_globals, _) = GlobalSymbols::new(&old_table);
+    let (new_globals, _) = GlobalSymbols::new(&new_table);
+    let diff = diff_symbols(&old_globals, &new_globals);
+
+    let old_names = diff
+        .renamed
+        .iter()
+        .map(|(old_name, new_name)| (new_name.as_str(), old_name.as_str()))
+        .collect::<HashMap<_, _>>();
+
+    let symbols = diff
+        .changes
+        .iter()
+        .map(|change| {
+            let old_name = old_names
+                .get(change.name.as_str())
+                .copied()
+                .unwrap_or(&change.name);
+
+            let before = match change.change.kind {
+                ChangeKind::Added => None,
+                _ => old_globals.symbols[old_name]
+                    .keys()
+                    .next()
+                    .and_then(|file| SymbolContents::find(old, file, old_name)),
+            };
+
+            // Prefer a copy of the symbol that actually changed
+            let after = change
+                .files
+                .first()
+                .and_then(|file| SymbolContents::find(new, file, &change.name));
+
+            let (relocations_added, relocations_removed) = match (&before, &after) {
+                (Some(before), Some(after)) => (
+                    multiset_difference(&after.targets, &before.targets),
+                    multiset_difference(&before.targets, &after.targets),
+                ),
+                (None, Some(after)) => (after.targets.clone(), vec![]),
+                (Some(before), None) => (vec![], before.targets.clone()),
+                (None, None) => (vec![], vec![]),
+            };
+
+            SymbolDiff {
+                name: change.name.clone(),
+                demangled: demangle(&change.name),
+                flavor: SymbolName::new(&change.name).flavor(),
+                kind: change.change.kind,
+                class: change.change.class,
+                old_object: before.as_ref().map(|b| b.object.clone()),
+                new_object: after.as_ref().map(|a| a.object.clone()),
+                bytes: match (&before, &after) {
+                    (Some(before), Some(after)) => {
+                        Some(ByteDiff::new(&before.masked, &after.masked))
+                    }
+                    _ => None,
+                },
+                relocations_added,
+                relocations_removed,
+            }
+        })
+        .collect();
+
+    SnapshotDiff {
+        symbols,
+        parents: new_globals.parents,
+    }
+}
+
+/// A saved generation only has fingerprints left, so there are no bytes or relocations to compare
+impl From<ObjectDiff> for SnapshotDiff {
+    fn from(diff: ObjectDiff) -> Self {
+        let objects = diff
+            .modified_files
+            .iter()
+            .flat_map(|(path, names)| names.iter().map(move |name| (name, file_name(path))))
+            .collect::<HashMap<_, _>>();
+
+        let symbols = diff
+            .changes
+            .iter()
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .map(|(name, change)| SymbolDiff {
+                name: name.clone(),
+                demangled: demangle(name),
+                flavor: SymbolName::new(name).flavor(),
+                kind: change.kind,
+                class: change.class,
+                old_object: None,
+                new_object: objects.get(name).cloned(),
+                bytes: None,
+                relocations_added: vec![],
+                relocations_removed: vec![],
+            })
+            .collect();
+
+        SnapshotDiff {
+            symbols,
+            parents: diff.parents,
+        }
+    }
+}
+
+/// Whatever is in `a` more times than it's in `b`
+fn multiset_difference(a: &[String], b: &[String]) -> Vec<String> {
+    let mut remaining = b.iter().counts();
+    a.iter()
+        .filter(|item| match remaining.get_mut(item) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{elf_with_functions, table, TestFn};
+    use super::*;
+    use clap::Parser;
+
+    fn snapshot(funcs: &[TestFn]) -> Snapshot {
+        let loaded = LoadedFile::from_bytes(elf_with_functions(funcs)).unwrap();
+        Snapshot {
+            objects: BTreeMap::from([("lib.o".to_string(), loaded)]),
+        }
+    }
+
+    #[test]
+    fn reports_byte_and_relocation_differences() {
+        let call = [0xe8, 0, 0, 0, 0, 0xc3];
+        let old = snapshot(&[
+            ("foo", &call, &[(1, "bar", -4)]),
+            ("gone", &[0xc3], &[]),
+            ("same", &[0x90, 0xc3], &[]),
+        ]);
+        let new = snapshot(&[
+            ("foo", &[0xe8, 0, 0, 0, 0, 0x90, 0xc3], &[(1, "baz", -4)]),
+            ("fresh", &[0xc3], &[]),
+            ("same", &[0x90, 0xc3], &[]),
+        ]);
+
+        let diffs = diff_snapshots(&old, &new).symbols;
+        let summary = diffs
+            .iter()
+            .map(|d| (d.name.as_str(), d.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("foo", ChangeKind::Modified),
+                ("fresh", ChangeKind::Added),
+                ("gone", ChangeKind::Removed),
+            ]
+        );
+
+        let foo = &diffs[0];
+        assert_eq!(
+            foo.bytes,
+            Some(ByteDiff {
+                old_size: 6,
+                new_size: 7,
+                differing: 2,
+                first_difference: Some(5),
+            })
+        );
+        assert_eq!(foo.relocations_added, ["baz-4"]);
+        assert_eq!(foo.relocations_removed, ["bar-4"]);
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        diff: DiffArgs,
+    }
+
+    #[test]
+    fn diffs_against_a_saved_generation() {
+        let cli = Cli::try_parse_from(["diff", "--against", "2"]).unwrap();
+        assert_eq!(cli.diff.against, Some(2));
+        assert!(Cli::try_parse_from(["diff", "old.o"]).is_err());
+        assert!(Cli::try_parse_from(["diff", "--against", "2", "old.o", "new.o"]).is_err());
+
+        let old = table(&[("a.o", &[("kept", 0), ("gone", 0)])]);
+        let new = table(&[("a.o", &[("kept", 1), ("fresh", 0)])]);
+        let diff: SnapshotDiff = ObjectDiff::between(PathBuf::from("objs"), new, &old).into();
+
+        let summary = diff
+            .symbols
+            .iter()
+            .map(|d| (d.name.as_str(), d.kind, d.new_object.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("fresh", ChangeKind::Added, Some("a.o")),
+                ("gone", ChangeKind::Removed, None),
+                ("kept", ChangeKind::Modified, Some("a.o")),
+            ]
+        );
+        assert!(diff.symbols.iter().all(|d| d.bytes.is_none()));
+    }
+}
@@ -0,0 +1,382 @@
+//! Source locations of changed symbols, read from the DWARF line tables
+//!
+//! The dev profile builds without debuginfo to keep builds fast, so this only kicks in when the app
+//! is built with line tables (`cargo hotreload --line-info`).
+//!
+//! Addresses in the line tables of an object aren't final: on ELF every function section starts at
+//! zero and the line table points into them through relocations. We lay the code sections out one
+//! after the other and apply those relocations ourselves so each row lands on a unique address.
+
+use super::*;
+use gimli::{EndianSlice, RunTimeEndian};
+use std::{borrow::Cow, fmt};
+
+/// Set for the linker when the app is built with line tables
+pub const LINE_INFO_ENV: &str = "HOTRELOAD_LINE_INFO";
+
+/// The lines of a source file a symbol was compiled from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceRange {
+    pub file: PathBuf,
+    pub first_line: u64,
+    pub last_line: u64,
+}
+
+impl fmt::Display for SourceRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self
+            .file
+            .strip_prefix(crate::workspace_root())
+            .unwrap_or(&self.file);
+
+        match self.first_line == self.last_line {
+            true => write!(f, "{}:{}", file.display(), self.first_line),
+            false => write!(
+                f,
+                "{}:{}-{}",
+                file.display(),
+                self.first_line,
+                self.last_line
+            ),
+        }
+    }
+}
+
+impl ObjectDiff {
+    /// Where each modified symbol came from, by demangled name
+    pub(super) fn source_ranges(&self) -> BTreeMap<String, SourceRange> {
+        let mut ranges = BTreeMap::new();
+
+        for (path, symbols) in self.modified_files.iter() {
+            let lines =
+                LoadedFile::open(path.clone()).and_then(|loaded| symbol_lines(loaded.file()));
+
+            let lines = match lines {
+                Ok(lines) => lines,
+                Err(err) => {
+                    println!("No line info for {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            for sym in symbols.iter() {
+                if let Some(range) = lines.get(sym) {
+                    ranges.insert(crate::symbol::demangle(sym), range.clone());
+                }
+            }
+        }
+
+        ranges
+    }
+}
+
+/// The source range of every function in an object that has line info
+fn symbol_lines(file: &File) -> Result<HashMap<String, SourceRange>> {
+    let layout = CodeLayout::new(file);
+    let endian = match file.is_little_endian() {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+
+    let sections = gimli::DwarfSections::load(|id| layout.load(file, id.name()))?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    // symbol -> (its file, every line of that file it covers)
+    let mut lines = HashMap::<&str, (PathBuf, Vec<u64>)>::new();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let unit = unit.unit_ref(&dwarf);
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| PathBuf::from(dir.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            // The end of a sequence is the first address past it, which is someone else's code
+            if row.end_sequence() {
+                continue;
+            }
+
+            let (Some(line), Some(entry)) = (row.line(), row.file(header)) else {
+                continue;
+            };
+
+            let Some(sym) = layout.symbol_at(row.address()) else {
+                continue;
+            };
+
+            let mut path = comp_dir.clone();
+            if let Some(dir) = entry.directory(header) {
+                path.push(unit.attr_string(dir)?.to_string_lossy().as_ref());
+            }
+            path.push(
+                unit.attr_string(entry.path_name())?
+                    .to_string_lossy()
+                    .as_ref(),
+            );
+
+            // Inlined code points into other files. The first row of a function is its own
+            // prologue, so that's the file we go with.
+            let (file, seen) = lines.entry(sym).or_insert_with(|| (path.clone(), vec![]));
+            if *file == path {
+                seen.push(line.get());
+            }
+        }
+    }
+
+    Ok(lines
+        .into_iter()
+        .filter_map(|(sym, (file, seen))| {
+            let range = SourceRange {
+                file,
+                first_line: *seen.iter().min()?,
+                last_line: *seen.iter().max()?,
+            };
+            Some((sym.to_string(), range))
+        })
+        .collect())
+}
+
+/// Every code section of an object placed at its own address
+pub(super) struct CodeLayout<'a> {
+    /// section -> where we put it
+    bases: HashMap<SectionIndex, u64>,
+
+    /// `(start, end, name)` of every function, sorted
+    symbols: Vec<(u64, u64, &'a str)>,
+}
+
+impl<'a> CodeLayout<'a> {
+    pub(super) fn new(file: &'a File<'a>) -> Self {
+        // Mach-O objects are already laid out, ELF ones have every section at zero
+        let mut bases = HashMap::new();
+        let mut next = 0;
+        for section in file.sections().filter(|s| s.kind() == SectionKind::Text) {
+            let base = match file.format() {
+                BinaryFormat::MachO => section.address(),
+                _ => next,
+            };
+            bases.insert(section.index(), base);
+            next = base + section.size();
+        }
+
+        let mut symbols = file
+            .symbols()
+            .filter(|s| s.kind() == SymbolKind::Text && s.is_definition())
+            .filter_map(|s| {
+                let section = file.section_by_index(s.section_index()?).ok()?;
+                let start = bases.get(&section.index())? + s.address() - section.address();
+                Some((start, start + s.size().max(1), s.name().ok()?))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort();
+
+        Self { bases, symbols }
+    }
+
+    fn symbol_at(&self, address: u64) -> Option<&'a str> {
+        let idx = self
+            .symbols
+            .partition_point(|(start, _, _)| *start <= address);
+        let (_, end, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        (address < *end).then_some(*name)
+    }
+
+    /// Load a debug section with its explicit-addend relocations applied against our layout
+    pub(super) fn load(&self, file: &'a File<'a>, name: &str) -> Result<Cow<'a, [u8]>> {
+        let Some(section) = file.section_by_name(name) else {
+            return Ok(Cow::Borrowed(&[]));
+        };
+
+        let mut data = section.uncompressed_data()?;
+        for (offset, reloc) in section.relocations() {
+            // Implicit addends (Mach-O) already hold the final value
+            if reloc.kind() != RelocationKind::Absolute || reloc.has_implicit_addend() {
+                continue;
+            }
+
+            let RelocationTarget::Symbol(idx) = reloc.target() else {
+                continue;
+            };
+            let sym = file.symbol_by_index(idx)?;
+
+            // Code moves with our layout, other debug sections don't move at all
+            let base = sym
+                .section_index()
+                .and_then(|idx| Some((self.bases.get(&idx)?, file.section_by_index(idx).ok()?)))
+                .map(|(base, section)| base - section.address())
+                .unwrap_or_default();
+
+            let value = (base + sym.address()).wrapping_add_signed(reloc.addend());
+            let bytes = match (reloc.size(), file.is_little_endian()) {
+                (32, true) => (value as u32).to_le_bytes().to_vec(),
+                (32, false) => (value as u32).to_be_bytes().to_vec(),
+                (64, true) => value.to_le_bytes().to_vec(),
+                (64, false) => value.to_be_bytes().to_vec(),
+                _ => continue,
+            };
+
+            let offset = offset as usize;
+            if let Some(field) = data.to_mut().get_mut(offset..offset + bytes.len()) {
+                field.copy_from_slice(&bytes);
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use gimli::write::{
+        Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Sections, Writer,
+    };
+    use gimli::{Encoding, Format, LineEncoding};
+    use object::SymbolScope;
+
+    /// An ELF object with `foo` and `bar` in their own sections, and a line table that covers
+    /// `foo` with lines 10-12 of `src/main.rs` and `bar` with line 20 plus an inlined line of
+    /// `core.rs`
+    fn object_with_lines() -> Vec<u8> {
+        let mut obj = TestObject::elf();
+
+        let mut sections = vec![];
+        for name in ["foo", "bar"] {
+            let (_, section) = obj.function(name, &[0x90; 8], SymbolScope::Linkage);
+            sections.push(obj.section_symbol(section));
+        }
+
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: 8,
+        };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let dir = LineString::String(b"/work".to_vec());
+        let file = LineString::String(b"main.rs".to_vec());
+        let mut program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            dir.clone(),
+            file.clone(),
+            None,
+        );
+        let src = program.default_directory();
+        let main = program.add_file(LineString::String(b"src/main.rs".to_vec()), src, None);
+        let core = program.add_file(LineString::String(b"core.rs".to_vec()), src, None);
+
+        // `Address::Symbol` indexes into `sections`
+        let rows: [&[(u64, u64, _)]; 2] = [
+            &[(0, 10, main), (2, 12, main), (4, 11, main)],
+            &[(0, 20, main), (4, 900, core)],
+        ];
+        for (idx, rows) in rows.iter().enumerate() {
+            program.begin_sequence(Some(Address::Symbol {
+                symbol: idx,
+                addend: 0,
+            }));
+            for (offset, line, file) in rows.iter() {
+                program.row().address_offset = *offset;
+                program.row().line = *line;
+                program.row().file = *file;
+                program.generate_row();
+            }
+            program.end_sequence(8);
+        }
+        dwarf.unit.line_program = program;
+        let root = dwarf.unit.root();
+        dwarf.unit.get_mut(root).set(
+            gimli::DW_AT_comp_dir,
+            AttributeValue::String(b"/work".to_vec()),
+        );
+
+        // Record where the line program points at the functions so we can emit relocations
+        let mut out = Sections::new(RelocWriter {
+            inner: EndianVec::new(gimli::LittleEndian),
+            relocs: vec![],
+        });
+        dwarf.write(&mut out).unwrap();
+
+        out.for_each_mut(|id, section| -> gimli::write::Result<()> {
+            if section.inner.slice().is_empty() {
+                return Ok(());
+            }
+
+            let idx = obj.add_section(vec![], id.name().as_bytes().to_vec(), SectionKind::Debug);
+            obj.append_section_data(idx, section.inner.slice(), 1);
+            for (offset, symbol, addend) in section.relocs.iter() {
+                let symbol = sections[*symbol];
+                obj.relocate(idx, *offset, symbol, *addend, object::elf::R_X86_64_64);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        obj.write()
+    }
+
+    /// Writes symbol addresses as zero and keeps them as relocations instead
+    #[derive(Clone)]
+    struct RelocWriter {
+        inner: EndianVec<gimli::LittleEndian>,
+        relocs: Vec<(u64, usize, i64)>,
+    }
+
+    impl Writer for RelocWriter {
+        type Endian = gimli::LittleEndian;
+
+        fn endian(&self) -> Self::Endian {
+            gimli::LittleEndian
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+            self.inner.write(bytes)
+        }
+
+        fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+            self.inner.write_at(offset, bytes)
+        }
+
+        fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+            match address {
+                Address::Constant(value) => self.inner.write_udata(value, size),
+                Address::Symbol { symbol, addend } => {
+                    self.relocs.push((self.len() as u64, symbol, addend));
+                    self.inner.write_udata(0, size)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn maps_function_sections_to_their_lines() {
+        let data = object_with_lines();
+        let file = File::parse(&*data).unwrap();
+        let lines = symbol_lines(&file).unwrap();
+
+        let range = |first_line, last_line| SourceRange {
+            file: PathBuf::from("/work/src/main.rs"),
+            first_line,
+            last_line,
+        };
+
+        // Both functions start at zero in their own section, they mustn't land on top of each other
+        assert_eq!(lines["foo"], range(10, 12));
+
+        // The inlined line from another file doesn't stretch the range
+        assert_eq!(lines["bar"], range(20, 20));
+    }
+}
@@ -0,0 +1,390 @@
+//! Figure out which symbol a relocation actually points at
+//!
+//! Relocations against a named symbol are easy. Relocations against a section (or an ELF section
+//! symbol) only tell us the section - where in the section is either in the relocation's addend or
+//! baked into the bytes being relocated, which on AArch64 means pulling it back out of the
+//! instruction. Once we have an address we pick the nearest symbol defined at or before it.
+
+use super::*;
+use std::fmt::Display;
+
+/// A relocation we couldn't pin to a symbol. These are reported instead of dropped on the floor so
+/// we know when the call graph has holes in it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnresolvedReloc {
+    /// The symbol the relocation lives in
+    pub symbol: String,
+
+    /// Offset of the relocation within its section
+    pub offset: u64,
+
+    pub reason: String,
+}
+
+impl Display for UnresolvedReloc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = crate::symbol::demangle(&self.symbol);
+        write!(f, "{symbol}+{:#x}: {}", self.offset, self.reason)
+    }
+}
+
+pub(super) struct TargetResolver<'a> {
+    arch: Architecture,
+
+    /// section -> address -> the symbol defined there
+    defs: HashMap<SectionIndex, BTreeMap<u64, &'a str>>,
+}
+
+impl<'a> TargetResolver<'a> {
+    pub fn new(file: &'a File<'a>) -> Self {
+        let mut defs = HashMap::<SectionIndex, BTreeMap<u64, &'a str>>::new();
+
+        // If a few symbols share an address, prefer the global one
+        let symbols = file
+            .symbols()
+            .filter(|s| s.is_definition())
+            .filter(|s| !matches!(s.kind(), SymbolKind::Section | SymbolKind::File))
+            .sorted_by_key(|s| (s.is_local(), s.index().0));
+
+        for sym in symbols {
+            let (Some(section), Ok(name)) = (sym.section_index(), sym.name()) else {
+                continue;
+            };
+
+            if name.is_empty() {
+                continue;
+            }
+
+            defs.entry(section)
+                .or_default()
+                .entry(sym.address())
+                .or_insert(name);
+        }
+
+        Self {
+            arch: file.architecture(),
+            defs,
+        }
+    }
+
+    /// Find the symbol that a relocation at `offset` within `sym`'s section points at
+    pub fn resolve(
+        &self,
+        file: &'a File<'a>,
+        sym: &RelocatedSymbol<'a>,
+        offset: u64,
+        reloc: &Relocation,
+    ) -> Result<&'a str, String> {
+        let section = match reloc.target() {
+            RelocationTarget::Symbol(idx) => {
+                let target = file.symbol_by_index(idx).map_err(|e| e.to_string())?;
+                if target.kind() != SymbolKind::Section {
+                    return target.name().map_err(|e| e.to_string());
+                }
+
+                target
+                    .section_index()
+                    .ok_or("section symbol without a section")?
+            }
+            RelocationTarget::Section(idx) => idx,
+            RelocationTarget::Absolute => return Err("absolute relocation".to_string()),
+            target => return Err(format!("unsupported relocation target {target:?}")),
+        };
+
+        let address = self.target_address(file, sym, offset, reloc, section)?;
+        self.containing(file, section, address)
+    }
+
+    /// The nearest symbol defined at or before `address` in the section
+    fn containing(
+        &self,
+        file: &'a File<'a>,
+        section: SectionIndex,
+        address: u64,
+    ) -> Result<&'a str, String> {
+        let Some(defs) = self.defs.get(&section) else {
+            // Nothing is defined in the section so the section itself is the target, like the
+            // `.rodata.<anon>` sections rustc emits for each constant on ELF
+            return file
+                .section_by_index(section)
+                .and_then(|s| s.name())
+                .map_err(|e| e.to_string());
+        };
+
+        defs.range(..=address)
+            .next_back()
+            .map(|(_, name)| *name)
+            .ok_or_else(|| format!("{address:#x} is before the first symbol in its section"))
+    }
+
+    /// Where the relocation points, in the same address space as the object's symbols
+    fn target_address(
+        &self,
+        file: &'a File<'a>,
+        sym: &RelocatedSymbol<'a>,
+        offset: u64,
+        reloc: &Relocation,
+        target_section: SectionIndex,
+    ) -> Result<u64, String> {
+        let section = file
+            .section_by_index(sym.section)
+            .map_err(|e| e.to_string())?;
+        let data = section.data().map_err(|e| e.to_string())?;
+        let place = section.address() + offset;
+        let width = reloc_width(self.arch, reloc);
+        let pc_relative = matches!(
+            reloc.kind(),
+            RelocationKind::Relative | RelocationKind::PltRelative | RelocationKind::GotRelative
+        );
+
+        // ELF - the addend is the offset into the target
+        if !reloc.has_implicit_addend() {
+            let base = file
+                .section_by_index(target_section)
+                .map_err(|e| e.to_string())?
+                .address();
+            let address = base.wrapping_add_signed(reloc.addend());
+
+            // x86 measures displacements from the end of the instruction and the addend is biased
+            // to match. The field is almost always the last thing in the instruction.
+            return match pc_relative
+                && matches!(self.arch, Architecture::X86_64 | Architecture::I386)
+            {
+                true => Ok(address.wrapping_add(width as u64)),
+                false => Ok(address),
+            };
+        }
+
+        let field = data
+            .get(offset as usize..offset as usize + width)
+            .ok_or("relocation runs past the end of its section")?;
+
+        if let Some(insn) = Aarch64Insn::of(self.arch, reloc) {
+            return self.aarch64_target(data, section.address(), sym, offset, reloc, insn);
+        }
+
+        let value = match field {
+            [a, b, c, d] => i32::from_le_bytes([*a, *b, *c, *d]) as i64,
+            bytes @ [_, _, _, _, _, _, _, _] => i64::from_le_bytes(bytes.try_into().unwrap()),
+            _ => return Err(format!("can't read a {width} byte addend")),
+        };
+
+        // Mach-O - the field holds the target address, or its distance from the place. `object`
+        // folds the distance from the place to the end of the instruction into the addend.
+        match pc_relative {
+            true => Ok(place.wrapping_add_signed(value.wrapping_sub(reloc.addend()))),
+            false => Ok(value.wrapping_add(reloc.addend()) as u64),
+        }
+    }
+
+    /// AArch64 addresses are split across an `adrp` for the page and an `add`/`ldr` for the offset
+    /// into it, so we need both halves to know where we're pointing.
+    fn aarch64_target(
+        &self,
+        data: &[u8],
+        section_address: u64,
+        sym: &RelocatedSymbol<'a>,
+        offset: u64,
+        reloc: &Relocation,
+        insn: Aarch64Insn,
+    ) -> Result<u64, String> {
+        let word = |offset: u64| {
+            data.get(offset as usize..offset as usize + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or("instruction runs past the end of its section")
+        };
+
+        // The other half of the pair points at the same target
+        let partner = |kind: Aarch64Insn| {
+            sym.relocations
+                .iter()
+                .filter(|(_, r)| r.target() == reloc.target())
+                .filter(|(_, r)| Aarch64Insn::of(self.arch, r) == Some(kind))
+                .map(|(o, _)| *o)
+                .min_by_key(|o| o.abs_diff(offset))
+        };
+
+        match insn {
+            Aarch64Insn::Branch26 => Ok(branch26_target(section_address + offset, word(offset)?)),
+            Aarch64Insn::Page21 => {
+                let lo =
+                    partner(Aarch64Insn::PageOff12).ok_or("adrp without a matching page offset")?;
+                Ok(adrp_page(section_address + offset, word(offset)?) + pageoff12(word(lo)?))
+            }
+            Aarch64Insn::PageOff12 => {
+                let hi = partner(Aarch64Insn::Page21).ok_or("page offset without an adrp")?;
+                Ok(adrp_page(section_address + hi, word(hi)?) + pageoff12(word(offset)?))
+            }
+        }
+    }
+}
+
+/// The AArch64 instruction relocations whose addend lives in the instruction's immediate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Aarch64Insn {
+    /// `b`/`bl`
+    Branch26,
+
+    /// `adrp`
+    Page21,
+
+    /// `add`/`ldr`/`str` with an unsigned immediate
+    PageOff12,
+}
+
+impl Aarch64Insn {
+    pub fn of(arch: Architecture, reloc: &Relocation) -> Option<Self> {
+        use object::macho::*;
+
+        if arch != Architecture::Aarch64 {
+            return None;
+        }
+
+        match reloc.flags() {
+            RelocationFlags::MachO { r_type, .. } => match r_type {
+                ARM64_RELOC_BRANCH26 => Some(Self::Branch26),
+                ARM64_RELOC_PAGE21 | ARM64_RELOC_GOT_LOAD_PAGE21 | ARM64_RELOC_TLVP_LOAD_PAGE21 => {
+                    Some(Self::Page21)
+                }
+                ARM64_RELOC_PAGEOFF12
+                | ARM64_RELOC_GOT_LOAD_PAGEOFF12
+                | ARM64_RELOC_TLVP_LOAD_PAGEOFF12 => Some(Self::PageOff12),
+                _ => None,
+            },
+
+            // ELF uses explicit addends, but the sizes still tell us which instruction it is
+            _ => match reloc.size() {
+                26 => Some(Self::Branch26),
+                21 => Some(Self::Page21),
+                12 => Some(Self::PageOff12),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value as i64) << (64 - bits)) >> (64 - bits)
+}
+
+/// `b`/`bl` - a signed word offset from the instruction
+fn branch26_target(place: u64, insn: u32) -> u64 {
+    place.wrapping_add_signed(sign_extend(insn & 0x03ff_ffff, 26) << 2)
+}
+
+/// `adrp` - a signed page offset from the instruction's page
+fn adrp_page(place: u64, insn: u32) -> u64 {
+    let immlo = (insn >> 29) & 0b11;
+    let immhi = (insn >> 5) & 0x7ffff;
+    let pages = sign_extend((immhi << 2) | immlo, 21);
+    (place & !0xfff).wrapping_add_signed(pages << 12)
+}
+
+/// The low 12 bits of an address from an `add` or a load/store. Loads and stores scale the
+/// immediate by the size of the access.
+fn pageoff12(insn: u32) -> u64 {
+    let imm = ((insn >> 10) & 0xfff) as u64;
+
+    // Load/store register (unsigned immediate)
+    if insn & 0x3b00_0000 == 0x3900_0000 {
+        let size = insn >> 30;
+        let vector = (insn >> 26) & 1 == 1;
+        let opc_hi = (insn >> 23) & 1 == 1;
+
+        // 128-bit vector loads are size 0 with the top opc bit set
+        let scale = match vector && size == 0 && opc_hi {
+            true => 4,
+            false => size,
+        };
+
+        return imm << scale;
+    }
+
+    imm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use object::write::StandardSection;
+    use object::SymbolScope;
+
+    /// `foo` referencing a `.rodata` with `A` at 8 and `B` at 24 through the section symbol, the
+    /// way ELF objects refer to local data
+    fn elf_with_section_relocs(relocs: &[(u32, i64)]) -> Vec<u8> {
+        let mut obj = TestObject::elf();
+
+        let rodata = obj.section_id(StandardSection::ReadOnlyData);
+        obj.append_section_data(rodata, &[0; 32], 8);
+        for (name, value) in [("A", 8), ("B", 24)] {
+            obj.define(
+                name,
+                rodata,
+                value,
+                8,
+                SymbolKind::Data,
+                SymbolScope::Compilation,
+            );
+        }
+
+        let (_, text) = obj.function("foo", &[0x90; 64], SymbolScope::Linkage);
+        let symbol = obj.section_symbol(rodata);
+        for (idx, (r_type, addend)) in relocs.iter().enumerate() {
+            obj.relocate(text, idx as u64 * 8, symbol, *addend, *r_type);
+        }
+
+        obj.write()
+    }
+
+    #[test]
+    fn resolves_section_relative_targets_to_containing_symbol() {
+        use object::elf::{R_X86_64_64, R_X86_64_PC32};
+
+        let data = elf_with_section_relocs(&[
+            // Straight at `A`
+            (R_X86_64_64, 8),
+            // Into the middle of `B` - pc-relative addends are biased by the field width
+            (R_X86_64_PC32, 26 - 4),
+            // Before anything is defined
+            (R_X86_64_64, 0),
+        ]);
+        let file = File::parse(&*data).unwrap();
+        let resolver = TargetResolver::new(&file);
+
+        let text = file.section_by_name(".text.foo").unwrap();
+        let foo = section_symbols(&file, text.index()).pop().unwrap();
+
+        let resolved = foo
+            .relocations
+            .iter()
+            .sorted_by_key(|(offset, _)| *offset)
+            .map(|(offset, reloc)| resolver.resolve(&file, &foo, *offset, reloc))
+            .collect_vec();
+
+        assert_eq!(resolved[0], Ok("A"));
+        assert_eq!(resolved[1], Ok("B"));
+        assert!(resolved[2].is_err());
+    }
+
+    #[test]
+    fn decodes_aarch64_immediates() {
+        // bl #8
+        assert_eq!(branch26_target(0x1000, 0x9400_0002), 0x1008);
+
+        // bl #-4
+        assert_eq!(branch26_target(0x1000, 0x97ff_ffff), 0xffc);
+
+        // adrp x0, #0x1000
+        assert_eq!(adrp_page(0x1234, 0xb000_0000), 0x2000);
+
+        // add x0, x0, #0x10
+        assert_eq!(pageoff12(0x9100_4000), 0x10);
+
+        // ldr x0, [x0, #0x18]
+        assert_eq!(pageoff12(0xf940_0c00), 0x18);
+
+        // ldr q0, [x0, #0x20]
+        assert_eq!(pageoff12(0x3dc0_0800), 0x20);
+    }
+}
@@ -0,0 +1,198 @@
+//! Function signatures, read from the DWARF of an object
+//!
+//! A symbol name doesn't always change with the function's signature: v0 mangling leaves the
+//! types out, and so do the names of generic instances. Callers that weren't recompiled would then
+//! keep calling the new code the old way. The debuginfo still says what the types are, so when the
+//! app is built with it we fingerprint each function's parameter and return types by name.
+//!
+//! Line tables alone don't describe any types, so without full debuginfo there's nothing to go on.
+
+use super::lines::CodeLayout;
+use super::*;
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian, UnitOffset};
+use sha2::{Digest, Sha256};
+
+type Unit<'a, 'u> = gimli::UnitRef<'u, EndianSlice<'a, RunTimeEndian>>;
+
+/// symbol -> a hash of its return and parameter types
+pub(super) fn signatures(file: &File) -> Result<HashMap<String, [u8; 32]>> {
+    let mut signatures = HashMap::new();
+    if file.section_by_name(".debug_info").is_none() {
+        return Ok(signatures);
+    }
+
+    let layout = CodeLayout::new(file);
+    let endian = match file.is_little_endian() {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+    let sections = gimli::DwarfSections::load(|id| layout.load(file, id.name()))?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let unit = unit.unit_ref(&dwarf);
+
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram
+                || entry.attr_value(gimli::DW_AT_declaration)?.is_some()
+            {
+                continue;
+            }
+
+            let Some(name) = linkage_name(&unit, entry.offset())? else {
+                continue;
+            };
+
+            let mut hasher = Sha256::new();
+            hasher.update(type_name(
+                &unit,
+                declared(&unit, entry.offset(), gimli::DW_AT_type)?,
+                0,
+            )?);
+
+            let mut tree = unit.entries_tree(Some(entry.offset()))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let param = child.entry();
+                if param.tag() == gimli::DW_TAG_formal_parameter {
+                    let ty = declared(&unit, param.offset(), gimli::DW_AT_type)?;
+                    let ty = type_name(&unit, ty, 0)?;
+                    hasher.update(b"\0");
+                    hasher.update(ty);
+                }
+            }
+
+            signatures.insert(name, hasher.finalize().into());
+        }
+    }
+
+    Ok(signatures)
+}
+
+fn linkage_name(unit: &Unit, offset: UnitOffset) -> Result<Option<String>> {
+    let name = match declared(unit, offset, gimli::DW_AT_linkage_name)? {
+        Some(name) => Some(name),
+        None => declared(unit, offset, gimli::DW_AT_MIPS_linkage_name)?,
+    };
+
+    Ok(match name {
+        Some(name) => Some(unit.attr_string(name)?.to_string_lossy().into_owned()),
+        None => None,
+    })
+}
+
+/// An attribute of an entry, or of the declaration it points at. Concrete functions of methods
+/// and of inlined functions, and their parameters, leave their names and types to those.
+fn declared<'a>(
+    unit: &Unit<'a, '_>,
+    offset: UnitOffset,
+    attr: gimli::DwAt,
+) -> Result<Option<AttributeValue<EndianSlice<'a, RunTimeEndian>>>> {
+    let mut offset = offset;
+    for _ in 0..4 {
+        let entry = unit.entry(offset)?;
+        if let Some(value) = entry.attr_value(attr)? {
+            return Ok(Some(value));
+        }
+
+        let origin = match entry.attr_value(gimli::DW_AT_specification)? {
+            Some(origin) => Some(origin),
+            None => entry.attr_value(gimli::DW_AT_abstract_origin)?,
+        };
+        match origin {
+            Some(AttributeValue::UnitRef(origin)) => offset = origin,
+            _ => break,
+        }
+    }
+
+    Ok(None)
+}
+
+/// What a type is called in the source. Pointers and the like are named in Rust's DWARF, but
+/// whatever isn't is described through the type it wraps.
+fn type_name(
+    unit: &Unit,
+    ty: Option<AttributeValue<EndianSlice<RunTimeEndian>>>,
+    depth: usize,
+) -> Result<String> {
+    let Some(AttributeValue::UnitRef(offset)) = ty else {
+        return Ok("()".to_string());
+    };
+
+    let entry = unit.entry(offset)?;
+    if let Some(name) = entry.attr_value(gimli::DW_AT_name)? {
+        return Ok(unit.attr_string(name)?.to_string_lossy().into_owned());
+    }
+
+    let inner = match depth < 8 {
+        true => type_name(unit, entry.attr_value(gimli::DW_AT_type)?, depth + 1)?,
+        false => String::new(),
+    };
+    Ok(format!("{}({inner})", entry.tag()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use gimli::write::{self, DwarfUnit, EndianVec, Sections};
+    use gimli::{Encoding, Format};
+    use object::SymbolScope;
+
+    /// An ELF object with `foo` taking one argument of the given type, described in its DWARF
+    fn object_taking(param: &str) -> Vec<u8> {
+        let mut obj = TestObject::elf();
+        obj.function("foo", &[0xc3], SymbolScope::Linkage);
+
+        let mut dwarf = DwarfUnit::new(Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: 8,
+        });
+        let root = dwarf.unit.root();
+        let ty = dwarf.unit.add(root, gimli::DW_TAG_base_type);
+        dwarf.unit.get_mut(ty).set(
+            gimli::DW_AT_name,
+            write::AttributeValue::String(param.as_bytes().to_vec()),
+        );
+        let foo = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        dwarf.unit.get_mut(foo).set(
+            gimli::DW_AT_linkage_name,
+            write::AttributeValue::String(b"foo".to_vec()),
+        );
+        let arg = dwarf.unit.add(foo, gimli::DW_TAG_formal_parameter);
+        dwarf
+            .unit
+            .get_mut(arg)
+            .set(gimli::DW_AT_type, write::AttributeValue::UnitRef(ty));
+
+        let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        sections
+            .for_each(|id, data| -> write::Result<()> {
+                if !data.slice().is_empty() {
+                    let name = id.name().as_bytes().to_vec();
+                    let section = obj.add_section(vec![], name, SectionKind::Debug);
+                    obj.append_section_data(section, data.slice(), 1);
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        obj.write()
+    }
+
+    #[test]
+    fn signatures_follow_parameter_types() {
+        let signature = |param| {
+            let data = object_taking(param);
+            signatures(&File::parse(&*data).unwrap()).unwrap()["foo"]
+        };
+
+        assert_eq!(signature("u32"), signature("u32"));
+        assert_ne!(signature("u32"), signature("u64"));
+    }
+}
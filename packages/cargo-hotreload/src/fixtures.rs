@@ -0,0 +1,146 @@
+//! Object files for tests
+//!
+//! Everything here builds x86_64 ELF, which is what the tests parse no matter the host. Anything
+//! more specific than functions, statics, imports and relocations goes through the underlying
+//! writer directly.
+
+use object::{
+    write::{self, SectionId, StandardSection, SymbolId, SymbolSection},
+    Architecture, BinaryFormat, Endianness, RelocationFlags, SectionKind, SymbolFlags, SymbolKind,
+    SymbolScope,
+};
+use std::ops::{Deref, DerefMut};
+
+pub struct TestObject(write::Object<'static>);
+
+impl TestObject {
+    pub fn elf() -> Self {
+        Self(write::Object::new(
+            BinaryFormat::Elf,
+            Architecture::X86_64,
+            Endianness::Little,
+        ))
+    }
+
+    /// A function in a section of its own, like rustc emits them
+    pub fn function(
+        &mut self,
+        name: &str,
+        body: &[u8],
+        scope: SymbolScope,
+    ) -> (SymbolId, SectionId) {
+        let section = self
+            .0
+            .add_subsection(StandardSection::Text, name.as_bytes());
+        let offset = self.0.append_section_data(section, body, 16);
+        let symbol = self.define(
+            name,
+            section,
+            offset,
+            body.len() as u64,
+            SymbolKind::Text,
+            scope,
+        );
+        (symbol, section)
+    }
+
+    /// A static in a section of its own. `.bss` statics are as long as `data`, but left zeroed.
+    pub fn static_item(
+        &mut self,
+        name: &str,
+        kind: SectionKind,
+        data: &[u8],
+        scope: SymbolScope,
+    ) -> (SymbolId, SectionId) {
+        let prefix = match kind {
+            SectionKind::UninitializedData => ".bss",
+            SectionKind::Data => ".data",
+            _ => ".rodata",
+        };
+        let section = self
+            .0
+            .add_section(vec![], format!("{prefix}.{name}").into_bytes(), kind);
+        let size = data.len() as u64;
+        match kind {
+            SectionKind::UninitializedData => self.0.append_section_bss(section, size, 8),
+            _ => self.0.append_section_data(section, data, 8),
+        };
+        let symbol = self.define(name, section, 0, size, SymbolKind::Data, scope);
+        (symbol, section)
+    }
+
+    /// A symbol for `size` bytes at `value` in `section`
+    pub fn define(
+        &mut self,
+        name: &str,
+        section: SectionId,
+        value: u64,
+        size: u64,
+        kind: SymbolKind,
+        scope: SymbolScope,
+    ) -> SymbolId {
+        self.0.add_symbol(write::Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size,
+            kind,
+            scope,
+            weak: false,
+            section: SymbolSection::Section(section),
+            flags: SymbolFlags::None,
+        })
+    }
+
+    /// A function the object expects someone else to define
+    pub fn import(&mut self, name: &str, weak: bool) -> SymbolId {
+        self.0.add_symbol(write::Symbol {
+            name: name.as_bytes().to_vec(),
+            value: 0,
+            size: 0,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Dynamic,
+            weak,
+            section: SymbolSection::Undefined,
+            flags: SymbolFlags::None,
+        })
+    }
+
+    pub fn relocate(
+        &mut self,
+        section: SectionId,
+        offset: u64,
+        symbol: SymbolId,
+        addend: i64,
+        r_type: u32,
+    ) {
+        self.0
+            .add_relocation(
+                section,
+                write::Relocation {
+                    offset,
+                    symbol,
+                    addend,
+                    flags: RelocationFlags::Elf { r_type },
+                },
+            )
+            .unwrap();
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        self.0.write().unwrap()
+    }
+}
+
+impl Deref for TestObject {
+    type Target = write::Object<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for TestObject {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
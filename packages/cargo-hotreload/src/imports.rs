@@ -0,0 +1,418 @@
+//! Checking a patch's imports before the app tries to load it
+//!
+//! The patch is linked with undefined symbols left for the dynamic loader, which resolves them
+//! against whatever is already in the process. If one of them isn't there, loading the patch
+//! fails inside the app - so we look for that here and refuse to ship the patch instead.
+
+use anyhow::Context;
+use object::{
+    elf,
+    read::elf::{Dyn, ElfFile64, FileHeader},
+    Endianness, Object, ObjectSymbol,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Every symbol the running app can hand to a patch
+#[derive(Debug, Default)]
+pub struct SymbolProviders {
+    names: HashSet<String>,
+}
+
+impl SymbolProviders {
+    /// What the launched binary offers: its own exports, plus the exports of every library the
+    /// loader opens for it
+    ///
+    /// Only the dynamic symbol table counts - the loader never looks at `.symtab`, so a symbol that
+    /// is only there can't be bound by a patch.
+    ///
+    /// On ELF the libraries are found the way the loader finds them. Elsewhere we fall back to
+    /// the binary's own imports, since the libraries providing those are loaded for sure.
+    pub fn from_binary(path: &Path) -> anyhow::Result<Self> {
+        let mut providers = Self::default();
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        providers.add_exports(&file)?;
+
+        if let object::File::Elf64(elf) = &file {
+            for library in needed_libraries(path, elf)? {
+                providers.add_library(&library)?;
+            }
+            return Ok(providers);
+        }
+
+        for import in file.imports()? {
+            providers.insert(import.name());
+        }
+        for sym in file.dynamic_symbols() {
+            if sym.is_undefined() {
+                providers.insert(sym.name_bytes()?);
+            }
+        }
+
+        Ok(providers)
+    }
+
+    /// Record the exports of a patch the app has loaded, so later patches can link against it
+    pub fn add_library(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        self.add_exports(&file)
+    }
+
+    fn add_exports(&mut self, file: &object::File) -> anyhow::Result<()> {
+        for export in file.exports()? {
+            self.insert(export.name());
+        }
+        for sym in file.dynamic_symbols() {
+            if sym.is_definition() && sym.is_global() {
+                self.insert(sym.name_bytes()?);
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, name: &[u8]) {
+        if let Ok(name) = std::str::from_utf8(name) {
+            self.names.insert(name.to_string());
+        }
+    }
+
+    pub fn provides(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Leave the providers where the linker can find them, since it runs as a separate process
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut names = self.names.iter().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        std::fs::write(path, names.join("\n"))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let names = std::fs::read_to_string(path)
+            .with_context(|| format!("No symbol providers at {}", path.display()))?;
+        Ok(names.lines().collect())
+    }
+}
+
+impl<'a> FromIterator<&'a str> for SymbolProviders {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        Self {
+            names: iter.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+/// Every library the loader opens for an ELF binary: its `DT_NEEDED` entries and theirs in turn,
+/// resolved as ld.so(8) describes. Libraries the loader wouldn't find either are left out, since
+/// the app couldn't have started without them unless nothing needed their symbols.
+fn needed_libraries(binary: &Path, file: &ElfFile64<Endianness>) -> anyhow::Result<Vec<PathBuf>> {
+    let machine = file.elf_header().e_machine(file.endian());
+    let root = DynamicInfo::of(binary, file)?;
+    let binary_rpath = match root.runpath.is_empty() {
+        true => root.rpath.clone(),
+        false => Vec::new(),
+    };
+    let search = LibrarySearch::new(machine, binary_rpath);
+
+    let mut seen = HashSet::new();
+    let mut libraries = Vec::new();
+    let mut queue = VecDeque::from([root]);
+    while let Some(needer) = queue.pop_front() {
+        for name in needer.needed.iter() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let Some((path, data)) = search.find(name, &needer) else {
+                continue;
+            };
+            let library = ElfFile64::<Endianness>::parse(&*data)?;
+            queue.push_back(DynamicInfo::of(&path, &library)?);
+            libraries.push(path);
+        }
+    }
+
+    Ok(libraries)
+}
+
+/// What an ELF object's dynamic section tells the loader about finding its libraries
+struct DynamicInfo {
+    needed: Vec<String>,
+    rpath: Vec<PathBuf>,
+    runpath: Vec<PathBuf>,
+}
+
+impl DynamicInfo {
+    fn of(path: &Path, file: &ElfFile64<Endianness>) -> anyhow::Result<Self> {
+        let mut info = Self {
+            needed: Vec::new(),
+            rpath: Vec::new(),
+            runpath: Vec::new(),
+        };
+
+        let endian = file.endian();
+        let sections = file.elf_section_table();
+        let Some((entries, strtab)) = sections.dynamic(endian, file.data())? else {
+            return Ok(info);
+        };
+        let strings = sections.strings(endian, file.data(), strtab)?;
+
+        // `$ORIGIN` is the directory of the object naming the path
+        let origin = path.parent().unwrap_or(Path::new("."));
+        let search_path = |value: &str| {
+            value
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| {
+                    let dir = dir
+                        .replace("${ORIGIN}", &origin.to_string_lossy())
+                        .replace("$ORIGIN", &origin.to_string_lossy());
+                    PathBuf::from(dir)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for entry in entries {
+            if !entry.is_string(endian) {
+                continue;
+            }
+            let value = String::from_utf8_lossy(entry.string(endian, strings)?).to_string();
+            match entry.tag32(endian) {
+                Some(elf::DT_NEEDED) => info.needed.push(value),
+                Some(elf::DT_RPATH) => info.rpath.extend(search_path(&value)),
+                Some(elf::DT_RUNPATH) => info.runpath.extend(search_path(&value)),
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+/// Where the loader looks for a library, in the order it looks
+struct LibrarySearch {
+    machine: u16,
+
+    /// The executable's `DT_RPATH`, searched for every library that doesn't have a `DT_RUNPATH`
+    binary_rpath: Vec<PathBuf>,
+    ld_library_path: Vec<PathBuf>,
+
+    /// `/etc/ld.so.cache`, by library name
+    cache: HashMap<String, Vec<PathBuf>>,
+}
+
+impl LibrarySearch {
+    fn new(machine: u16, binary_rpath: Vec<PathBuf>) -> Self {
+        let ld_library_path = std::env::var_os("LD_LIBRARY_PATH")
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+
+        Self {
+            machine,
+            binary_rpath,
+            ld_library_path,
+            cache: ld_so_cache(),
+        }
+    }
+
+    /// The first candidate the loader would accept, and its contents
+    fn find(&self, name: &str, needer: &DynamicInfo) -> Option<(PathBuf, Vec<u8>)> {
+        // A name with a slash is a path, and it's opened as is
+        if name.contains('/') {
+            return self.loadable(PathBuf::from(name));
+        }
+
+        // `DT_RPATH` is ignored by objects that have a `DT_RUNPATH`
+        let rpath = match needer.runpath.is_empty() {
+            true => needer
+                .rpath
+                .iter()
+                .chain(self.binary_rpath.iter())
+                .collect(),
+            false => Vec::new(),
+        };
+
+        let dirs = rpath
+            .into_iter()
+            .chain(self.ld_library_path.iter())
+            .chain(needer.runpath.iter())
+            .map(|dir| dir.join(name));
+        let cached = self.cache.get(name).into_iter().flatten().cloned();
+        let defaults = ["/lib64", "/usr/lib64", "/lib", "/usr/lib"]
+            .into_iter()
+            .map(|dir| Path::new(dir).join(name));
+
+        dirs.chain(cached)
+            .chain(defaults)
+            .find_map(|path| self.loadable(path))
+    }
+
+    /// The loader skips libraries built for another architecture and keeps looking
+    fn loadable(&self, path: PathBuf) -> Option<(PathBuf, Vec<u8>)> {
+        let data = std::fs::read(&path).ok()?;
+        let file = ElfFile64::<Endianness>::parse(&*data).ok()?;
+        (file.elf_header().e_machine(file.endian()) == self.machine).then_some((path, data))
+    }
+}
+
+/// The libraries `ldconfig` indexed, by name. No cache just means the loader skips this step.
+fn ld_so_cache() -> HashMap<String, Vec<PathBuf>> {
+    let output = ["ldconfig", "/sbin/ldconfig"]
+        .into_iter()
+        .find_map(|ldconfig| Command::new(ldconfig).arg("-p").output().ok());
+    let Some(output) = output else {
+        return HashMap::new();
+    };
+
+    // "\tlibc.so.6 (libc6,x86-64) => /lib/x86_64-linux-gnu/libc.so.6"
+    let mut cache = HashMap::<String, Vec<PathBuf>>::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((entry, path)) = line.trim().split_once(" => ") else {
+            continue;
+        };
+        let Some(name) = entry.split_whitespace().next() else {
+            continue;
+        };
+        cache
+            .entry(name.to_string())
+            .or_default()
+            .push(PathBuf::from(path));
+    }
+    cache
+}
+
+/// The undefined symbols of a patch that nothing in the app provides
+///
+/// Weak imports are allowed to stay unresolved, and so are imports the patch pulls from a library
+/// it names itself - the loader will open that library alongside the patch.
+pub fn missing_symbols(patch: &Path, providers: &SymbolProviders) -> anyhow::Result<Vec<String>> {
+    let data = std::fs::read(patch)?;
+    let file = object::File::parse(&*data)
+        .with_context(|| format!("Failed to parse {}", patch.display()))?;
+
+    let from_named_library = file
+        .imports()?
+        .into_iter()
+        .filter(|import| !import.library().is_empty())
+        .map(|import| import.name())
+        .collect::<HashSet<_>>();
+
+    let mut missing = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|sym| sym.is_undefined() && !sym.is_weak())
+        .filter_map(|sym| sym.name().ok())
+        .filter(|name| !name.is_empty())
+        .filter(|name| !from_named_library.contains(name.as_bytes()))
+        .filter(|name| !providers.provides(name))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    missing.sort();
+    missing.dedup();
+    Ok(missing)
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use object::SymbolScope;
+
+    /// Link an object into a shared library, so its symbols end up where the loader looks
+    fn link_shared(obj: TestObject, path: &Path, libraries: &[&str]) {
+        let object = path.with_extension("o");
+        std::fs::write(&object, obj.write()).unwrap();
+        let out = std::process::Command::new("cc")
+            .args(["-shared", "-nostdlib", "-o"])
+            .arg(path)
+            .arg(&object)
+            .args(libraries)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    #[test]
+    fn reports_imports_nothing_provides() {
+        let dir = std::env::temp_dir().join(format!("hotreload-imports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A library the app needs but never calls into, found through the app's runpath
+        let mut needed = TestObject::elf();
+        needed.function("needed_only", &[0xc3], SymbolScope::Dynamic);
+        link_shared(
+            needed,
+            &dir.join("libneeded.so"),
+            &["-Wl,-soname,libneeded.so"],
+        );
+
+        let mut app = TestObject::elf();
+        app.function("app_helper", &[0xc3], SymbolScope::Dynamic);
+        app.function("hidden_helper", &[0xc3], SymbolScope::Linkage);
+        app.import("malloc", false);
+        let app_path = dir.join("app.so");
+        let search = format!("-L{}", dir.display());
+        link_shared(
+            app,
+            &app_path,
+            &[
+                "-Wl,--no-as-needed",
+                &search,
+                "-lneeded",
+                "-Wl,-rpath,$ORIGIN",
+                "-lc",
+            ],
+        );
+
+        let mut patch = TestObject::elf();
+        patch.import("app_helper", false);
+        patch.import("malloc", false);
+        patch.import("stripped_helper", false);
+        patch.import("optional_hook", true);
+        patch.import("hidden_helper", false);
+        patch.import("needed_only", false);
+        // libc provides it whether or not the app calls it
+        patch.import("qsort", false);
+        let patch_path = dir.join("patch.o");
+        std::fs::write(&patch_path, patch.write()).unwrap();
+
+        // Hidden symbols stay in `.symtab`, where the loader can't see them
+        let mut providers = SymbolProviders::from_binary(&app_path).unwrap();
+        assert_eq!(
+            missing_symbols(&patch_path, &providers).unwrap(),
+            ["hidden_helper", "stripped_helper"]
+        );
+
+        // A previous patch defining the symbol satisfies it
+        let mut earlier = TestObject::elf();
+        earlier.function("stripped_helper", &[0xc3], SymbolScope::Dynamic);
+        earlier.function("hidden_helper", &[0xc3], SymbolScope::Dynamic);
+        let earlier_path = dir.join("earlier.so");
+        link_shared(earlier, &earlier_path, &[]);
+
+        providers.add_library(&earlier_path).unwrap();
+        assert!(missing_symbols(&patch_path, &providers).unwrap().is_empty());
+
+        // The linker picks them up from disk
+        let saved = dir.join("providers.txt");
+        providers.save(&saved).unwrap();
+        let loaded = SymbolProviders::load(&saved).unwrap();
+        assert!(missing_symbols(&patch_path, &loaded).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,76 +1,94 @@
-use std::{path::PathBuf, process::Stdio, time::SystemTime};
+use capture::{CapturedRustc, CrateKey};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Context;
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::{
+    camino::Utf8PathBuf,
+    diagnostic::{Diagnostic, DiagnosticLevel},
+};
 use clap::Parser;
 use futures::StreamExt;
 use notify::{event::DataChange, Watcher};
+use object::{Architecture, BinaryFormat, Object};
 use serde::Deserialize;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
-    process::{Child, Command},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     time::Instant,
 };
 
+mod capture;
+mod diagnostics;
 mod diff;
+#[cfg(test)]
+mod fixtures;
+mod imports;
+mod report;
+mod strip;
+mod symbol;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Cargo is calling us as the rustc wrapper - record the invocation and pass it through
+    if let Ok(capture_dir) = std::env::var(capture::CAPTURE_DIR_ENV) {
+        return capture::wrap_rustc(capture_dir.into()).await;
+    }
+
     // Go through the linker if we need to
     if let Ok(action) = std::env::var("HOTRELOAD_LINK") {
         return link(action).await;
     }
 
-    hotreload_loop().await
+    let cli = Cli::parse_from(cargo_args());
+    match cli.command {
+        Some(Subcommand::Diff(args)) => args.run(),
+        None => hotreload_loop(cli.line_info, cli.load_objects).await,
+    }
 }
 
-async fn hotreload_loop() -> anyhow::Result<()> {
-    // Save the state of the rust files
-    let main_rs = PathBuf::from(workspace_root().join("packages/harness/src/main.rs"));
-    let mut contents = std::fs::read_to_string(&main_rs).unwrap();
+#[derive(Parser, Debug)]
+#[command(name = "cargo-hotreload", bin_name = "cargo hotreload")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Subcommand>,
 
-    // Modify the main.rs mtime so we skip "fresh" builds
-    // Basically `touch main.rs` in the directory
-    std::fs::File::open(&main_rs)?.set_modified(SystemTime::now())?;
+    /// Build the app with line tables so the reload report can say where each change is
+    #[arg(long)]
+    line_info: bool,
 
-    let cur_exe = std::env::current_exe()?;
-    let now = std::time::Instant::now();
-    let inital_build = Command::new("cargo")
-        .arg("rustc")
-        .arg("--package")
-        .arg("harness")
-        .arg("--bin")
-        .arg("harness")
-        .arg("--profile")
-        .arg("hotreload")
-        .arg("--message-format")
-        .arg("json-diagnostic-rendered-ansi")
-        .arg("--verbose")
-        .arg("--")
-        .arg(format!("-Clinker={}", cur_exe.canonicalize()?.display()))
-        .env("HOTRELOAD_LINK", "start")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()?;
+    /// Have the app load each patch's objects itself instead of the linked library (ELF only)
+    #[arg(long)]
+    load_objects: bool,
+}
 
-    let CargoOutputResult {
-        output_location: exe,
-        direct_rustc,
-    } = run_cargo_output(inital_build, false).await?;
-    println!("Initial build complete in: {:?}", now.elapsed());
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Compare two builds and show which symbols changed and how
+    Diff(diff::DiffArgs),
+}
 
-    // copy the exe and give it a "fat" name
-    let now = std::time::SystemTime::UNIX_EPOCH;
-    let fat_exe = exe.with_file_name(format!("fatharness-{}", now.elapsed().unwrap().as_millis()));
-    std::fs::copy(&exe, &fat_exe)?;
+/// Cargo runs us as `cargo-hotreload hotreload <args>`, so drop the subcommand name it passes along
+fn cargo_args() -> impl Iterator<Item = String> {
+    let mut args = std::env::args().peekable();
+    let bin = args.next();
+    if args.peek().map(|arg| arg.as_str()) == Some("hotreload") {
+        args.next();
+    }
+    bin.into_iter().chain(args)
+}
 
-    // Launch the fat exe. We'll overwrite the slim exe location, so this prevents the app from bugging out
-    let mut app = Command::new(fat_exe)
-        .stdin(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()?;
-    let mut app_stdin = app.stdin.take().unwrap();
+async fn hotreload_loop(line_info: bool, load_objects: bool) -> anyhow::Result<()> {
+    // Save the state of the rust files
+    let main_rs = PathBuf::from(workspace_root().join("packages/harness/src/main.rs"));
+    let mut contents = std::fs::read_to_string(&main_rs).unwrap();
+
+    let mut session = Session::start(&main_rs, line_info, load_objects).await?;
 
     let (tx, mut rx) = futures_channel::mpsc::unbounded();
     let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
@@ -79,6 +97,11 @@ async fn hotreload_loop() -> anyhow::Result<()> {
 
     watcher.watch(&main_rs, notify::RecursiveMode::NonRecursive)?;
 
+    // Manifests, build scripts and proc-macros need to go through cargo proper
+    for input in build_inputs()? {
+        watcher.watch(&input, notify::RecursiveMode::Recursive)?;
+    }
+
     while let Some(Ok(event)) = rx.next().await {
         if event.kind
             != notify::EventKind::Modify(notify::event::ModifyKind::Data(DataChange::Content))
@@ -86,15 +109,184 @@ async fn hotreload_loop() -> anyhow::Result<()> {
             continue;
         }
 
-        let new_contents = std::fs::read_to_string(&main_rs).unwrap();
-        if new_contents == contents {
-            println!("File changed but contents didn't change");
-            continue;
+        let outcome = if event.paths.contains(&main_rs) {
+            let new_contents = std::fs::read_to_string(&main_rs).unwrap();
+            if new_contents == contents {
+                println!("File changed but contents didn't change");
+                continue;
+            }
+            contents = new_contents;
+
+            println!("Fast reloading... ");
+            session.hotpatch().await
+        } else {
+            println!("Build inputs changed: {:?}", event.paths);
+            session.rebuild().await
+        };
+
+        // Only a patch the app actually loaded becomes the baseline for the next one
+        match outcome {
+            Ok(PatchOutcome::Patched) => {
+                if let Err(e) = diff::commit_pending_generation() {
+                    println!("Couldn't save the patch's fingerprints: {e:?}");
+                }
+            }
+            _ => diff::discard_pending_generation(),
         }
-        contents = new_contents;
 
-        println!("Fast reloading... ");
+        // Anything short of a patch the app can't take leaves it running as it is, so a typo
+        // doesn't cost a full rebuild
+        let reason = match outcome {
+            Ok(PatchOutcome::Patched | PatchOutcome::NoChanges) => continue,
+            Ok(PatchOutcome::RestartRequired(reason)) => reason,
+            Ok(PatchOutcome::CompileError(diagnostics)) => {
+                for diagnostic in diagnostics.iter() {
+                    print!("{diagnostic}");
+                }
+                println!("Build failed, fix the errors above and save again");
+                continue;
+            }
+            Err(e) => {
+                println!("{e:?}");
+                println!("Hot patch failed, the app keeps running its current code");
+                continue;
+            }
+        };
+
+        // The patch can't be applied, so fall back to a regular build and relaunch the app.
+        // If the full build fails too we keep the old app around.
+        println!("Restarting app: {reason}");
+        match Session::start(&main_rs, line_info, load_objects).await {
+            Ok(new_session) => session = new_session,
+            Err(e) => println!("Full rebuild failed, keeping the stale app: {e:?}"),
+        }
+    }
 
+    Ok(())
+}
+
+/// A running copy of the fat binary and the rustc invocations we use to patch it
+struct Session {
+    app: Child,
+    app_stdin: ChildStdin,
+
+    /// The app's answers to the patches we send it
+    acks: UnboundedReceiver<report::PatchAck>,
+
+    rustc_commands: BTreeMap<CrateKey, CapturedRustc>,
+
+    /// Whether the app is built with line tables
+    line_info: bool,
+
+    /// Whether to send the app the patch's objects along with the linked library
+    load_objects: bool,
+
+    /// Symbols the app and the patches it has loaded so far can resolve for the next patch
+    providers: imports::SymbolProviders,
+}
+
+enum PatchOutcome {
+    Patched,
+    NoChanges,
+    RestartRequired(String),
+
+    /// The user's code doesn't compile. Restarting won't help, so this waits for the next edit.
+    CompileError(Vec<String>),
+}
+
+impl Session {
+    /// Do a full build of the app and launch it.
+    ///
+    /// The linker caches the objects of this build, so they become the baseline for the next patch.
+    async fn start(main_rs: &Path, line_info: bool, load_objects: bool) -> anyhow::Result<Self> {
+        // Modify the main.rs mtime so we skip "fresh" builds
+        // Basically `touch main.rs` in the directory
+        std::fs::File::open(main_rs)?.set_modified(SystemTime::now())?;
+
+        let now = std::time::Instant::now();
+        let inital_build = cargo_rustc("start", line_info)?;
+
+        let CargoOutputResult {
+            output_location: exe,
+        } = run_cargo_output(inital_build, false).await?;
+        println!("Initial build complete in: {:?}", now.elapsed());
+
+        let rustc_commands = capture::load_captures(&capture_dir())?;
+
+        // copy the exe and give it a "fat" name
+        let now = std::time::SystemTime::UNIX_EPOCH;
+        let fat_exe =
+            exe.with_file_name(format!("fatharness-{}", now.elapsed().unwrap().as_millis()));
+        std::fs::copy(&exe, &fat_exe)?;
+        let providers = imports::SymbolProviders::from_binary(fat_exe.as_std_path())?;
+
+        // Launch the fat exe. We'll overwrite the slim exe location, so this prevents the app from bugging out
+        let mut app = Command::new(fat_exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let app_stdin = app.stdin.take().unwrap();
+        let (ack_tx, acks) = unbounded_channel();
+        tokio::spawn(forward_app_output(app.stdout.take().unwrap(), ack_tx));
+
+        let session = Self {
+            app,
+            app_stdin,
+            acks,
+            rustc_commands,
+            line_info,
+            load_objects,
+            providers,
+        };
+
+        // Make sure we actually caught the app's rustc before we start relying on it
+        session.app_rustc()?;
+
+        Ok(session)
+    }
+
+    /// The captured rustc invocation for the app itself
+    fn app_rustc(&self) -> anyhow::Result<&CapturedRustc> {
+        self.rustc_commands
+            .get(&CrateKey::new("harness", "bin"))
+            .context("No rustc invocation captured for the app")
+    }
+
+    /// Rebuild through cargo after a manifest, build script or proc-macro changed.
+    ///
+    /// This refreshes the captured rustc invocation. If the dependencies of the app changed we
+    /// can't patch since the patch only carries the app's own objects.
+    async fn rebuild(&mut self) -> anyhow::Result<PatchOutcome> {
+        _ = std::fs::remove_file(report::report_path());
+        self.providers.save(&providers_path())?;
+
+        let started = Instant::now();
+        let output_location =
+            match run_cargo_output(cargo_rustc("reload", self.line_info)?, false).await {
+                Ok(output) => output.output_location,
+                Err(err) => return Self::build_failure(err),
+            };
+
+        // Cargo decided the app was fresh so the linker never ran
+        if !report::report_path().exists() {
+            println!("Nothing to patch");
+            return Ok(PatchOutcome::NoChanges);
+        }
+
+        let old_externs = self.app_rustc()?.extern_args().join(" ");
+        self.rustc_commands = capture::load_captures(&capture_dir())?;
+        if self.app_rustc()?.extern_args().join(" ") != old_externs {
+            return Ok(PatchOutcome::RestartRequired(
+                "dependencies changed".to_string(),
+            ));
+        }
+
+        self.send_patch(output_location, started).await
+    }
+
+    /// Rebuild through rustc directly and send the resulting patch to the running app
+    async fn hotpatch(&mut self) -> anyhow::Result<PatchOutcome> {
         // going through rustc directly
         // .arg("rustc")
         // .arg("--package")
@@ -110,39 +302,283 @@ async fn hotreload_loop() -> anyhow::Result<()> {
         // .arg(format!("-Clinker={}", cur_exe.canonicalize()?.display()))
         // .arg(format!("-Cdebuginfo=0"))
 
-        let fast_build = Command::new(direct_rustc[0].clone())
-            .args(direct_rustc[1..].iter())
+        // The app went away on its own - nothing to patch
+        if let Some(status) = self.app.try_wait()? {
+            return Ok(PatchOutcome::RestartRequired(format!(
+                "app exited with {status}"
+            )));
+        }
+
+        // Clear out the last report so we don't pick up a stale one if the link never happens
+        _ = std::fs::remove_file(report::report_path());
+        self.providers.save(&providers_path())?;
+
+        let mut fast_build = self.app_rustc()?.command();
+        if self.line_info {
+            fast_build.env(diff::LINE_INFO_ENV, "1");
+        }
+
+        let fast_build = fast_build
             .env("HOTRELOAD_LINK", "reload")
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
 
         let started = Instant::now();
-        let output = run_cargo_output(fast_build, false).await;
-        let output = match output {
+        let output = match run_cargo_output(fast_build, false).await {
             Ok(output) => output.output_location,
-            Err(e) => {
-                println!("cargo failed: {e:?}");
-                continue;
-            }
+            Err(err) => return Self::build_failure(err),
         };
 
+        self.send_patch(output, started).await
+    }
+
+    /// Work out why a patch build failed: a link failure may still go away with a restart, but a
+    /// compile error has to be fixed in the source first
+    fn build_failure(err: anyhow::Error) -> anyhow::Result<PatchOutcome> {
+        if let Some(outcome) = Self::link_failure() {
+            return Ok(outcome);
+        }
+
+        match err.downcast::<BuildFailed>() {
+            Ok(failed) => Ok(PatchOutcome::CompileError(failed.diagnostics)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// If the build failed because the patch didn't link, show why. A failed link isn't a compile
+    /// error in the user's code, so a full restart still has a chance of working.
+    fn link_failure() -> Option<PatchOutcome> {
+        let report = report::ReloadReport::load(&report::report_path()).ok()?;
+        let failed = report
+            .link_diagnostics
+            .iter()
+            .any(|d| d.severity == diagnostics::Severity::Error);
+
+        if !failed {
+            return None;
+        }
+
+        report.print();
+        Some(PatchOutcome::RestartRequired(
+            "linking the patch failed".to_string(),
+        ))
+    }
+
+    /// Check the reload report and hand the freshly linked patch over to the app
+    async fn send_patch(
+        &mut self,
+        output: Utf8PathBuf,
+        started: Instant,
+    ) -> anyhow::Result<PatchOutcome> {
+        let now = std::time::SystemTime::UNIX_EPOCH;
         let output_temp =
             output.with_file_name(format!("output-{}", now.elapsed().unwrap().as_millis()));
-        std::fs::copy(&output, &output_temp).unwrap();
+        std::fs::copy(&output, &output_temp)?;
 
         println!("output: {:?}", output_temp);
 
-        // write the new object file to the stdin of the app
-        app_stdin
-            .write_all(format!("{}\n", output_temp).as_bytes())
+        let report = report::ReloadReport::load(&report::report_path())
+            .context("Linker didn't produce a reload report")?;
+        report.print();
+
+        if let report::Verdict::RestartRequired { reasons } = &report.verdict {
+            return Ok(PatchOutcome::RestartRequired(reasons.join(", ")));
+        }
+
+        // Loading a patch with an import the app can't satisfy would take the app down with it
+        let missing = imports::missing_symbols(output_temp.as_std_path(), &self.providers)?;
+        if !missing.is_empty() {
+            println!("❌ the patch needs symbols the app doesn't have:");
+            for name in missing.iter() {
+                println!("  {}", symbol::demangle(name));
+            }
+            return Ok(PatchOutcome::RestartRequired(format!(
+                "{} unresolved symbol(s) in the patch",
+                missing.len()
+            )));
+        }
+
+        let objects = match self.load_objects {
+            true => snapshot_objects(&output_temp.with_extension("objects"))?,
+            false => vec![],
+        };
+
+        // Tell the app where the new code is and which of its hot functions need to re-run
+        let message = report::PatchMessage {
+            library: output_temp.as_str(),
+            affected: &report.hot_functions,
+            objects: &objects,
+        };
+        self.app_stdin
+            .write_all(format!("{}\n", serde_json::to_string(&message)?).as_bytes())
             .await?;
+
+        // A patch only provides symbols to later ones once the app has actually loaded it
+        let ack = self.wait_for_ack(output_temp.as_std_path()).await?;
+        if let Some(error) = ack.error {
+            return Ok(PatchOutcome::RestartRequired(format!(
+                "the app couldn't load the patch: {error}"
+            )));
+        }
+        self.providers.add_library(output_temp.as_std_path())?;
         println!("took {:?}", started.elapsed());
+
+        Ok(PatchOutcome::Patched)
     }
 
-    drop(app);
+    /// Wait for the app to answer the patch for `library`, skipping answers to earlier patches we
+    /// gave up on
+    async fn wait_for_ack(&mut self, library: &Path) -> anyhow::Result<report::PatchAck> {
+        let acks = &mut self.acks;
+        let ack = async move {
+            loop {
+                match acks.recv().await {
+                    Some(ack) if ack.library == library => return Ok(ack),
+                    Some(_) => continue,
+                    None => anyhow::bail!("The app exited before loading the patch"),
+                }
+            }
+        };
 
-    Ok(())
+        tokio::time::timeout(ACK_TIMEOUT, ack)
+            .await
+            .context("The app didn't answer the patch")?
+    }
+}
+
+/// How long the app gets to load a patch
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pass the app's output through, holding back its answers to our patches
+async fn forward_app_output(stdout: ChildStdout, acks: UnboundedSender<report::PatchAck>) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match report::PatchAck::parse(&line) {
+            Some(ack) => _ = acks.send(ack),
+            None => println!("{line}"),
+        }
+    }
+}
+
+/// Where the linker leaves the objects it actually linked into the patch
+fn patch_objects_dir() -> PathBuf {
+    workspace_root().join("data").join("patch-objects")
+}
+
+/// Where the app's objects go once their private statics are exported
+fn app_objects_dir() -> PathBuf {
+    workspace_root().join("data").join("app-objects")
+}
+
+/// Where the driver leaves the symbols a patch can bind to, for the linker to strip against
+fn providers_path() -> PathBuf {
+    workspace_root().join("data").join("providers.txt")
+}
+
+/// Copy the objects the linker just saw somewhere the next patch won't overwrite them
+fn snapshot_objects(dir: &Utf8PathBuf) -> anyhow::Result<Vec<String>> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut objects = vec![];
+    for entry in std::fs::read_dir(patch_objects_dir())? {
+        let path = entry?.path();
+        let copy = dir.join(path.file_name().unwrap().to_string_lossy().as_ref());
+        std::fs::copy(&path, &copy)?;
+        objects.push(copy.into_string());
+    }
+
+    Ok(objects)
+}
+
+/// Build the app through `cargo rustc` with ourselves as the linker
+fn cargo_rustc(link_mode: &str, line_info: bool) -> anyhow::Result<Child> {
+    let cur_exe = std::env::current_exe()?;
+    let mut cargo = Command::new("cargo");
+    cargo
+        .arg("rustc")
+        .arg("--package")
+        .arg("harness")
+        .arg("--bin")
+        .arg("harness")
+        .arg("--profile")
+        .arg("hotreload")
+        .arg("--message-format")
+        .arg("json-diagnostic-rendered-ansi")
+        .arg("--")
+        .arg(format!("-Clinker={}", cur_exe.canonicalize()?.display()));
+
+    // The profile turns debuginfo off, but line tables are cheap enough to turn back on. The
+    // captured rustc invocation picks this up too.
+    if line_info {
+        cargo
+            .arg("-Cdebuginfo=line-tables-only")
+            .env(diff::LINE_INFO_ENV, "1");
+    }
+
+    let child = cargo
+        .env("HOTRELOAD_LINK", link_mode)
+        .env("RUSTC_WORKSPACE_WRAPPER", cur_exe.canonicalize()?)
+        .env(capture::CAPTURE_DIR_ENV, capture_dir())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    Ok(child)
+}
+
+/// Files that rustc alone can't pick up changes to: the manifests and lockfile, build scripts,
+/// and the sources of any proc-macro crates in the workspace that the app depends on.
+fn build_inputs() -> anyhow::Result<Vec<PathBuf>> {
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(workspace_root().join("Cargo.toml"))
+        .exec()?;
+
+    let mut inputs = vec![
+        workspace_root().join("Cargo.toml"),
+        workspace_root().join("Cargo.lock"),
+    ];
+
+    // Walk the dependency graph from the app to find the workspace packages it uses
+    let resolve = metadata.resolve.as_ref().context("No dependency graph")?;
+    let harness = metadata
+        .workspace_packages()
+        .into_iter()
+        .find(|p| p.name == "harness")
+        .context("No harness package")?;
+
+    let mut stack = vec![&harness.id];
+    let mut seen = HashSet::new();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        if let Some(node) = resolve.nodes.iter().find(|n| &n.id == id) {
+            stack.extend(node.dependencies.iter());
+        }
+    }
+
+    for package in metadata.workspace_packages() {
+        if !seen.contains(&package.id) {
+            continue;
+        }
+
+        inputs.push(package.manifest_path.clone().into());
+
+        for target in package.targets.iter() {
+            if target.is_custom_build() {
+                inputs.push(target.src_path.clone().into());
+            }
+
+            if target.is_proc_macro() {
+                inputs.push(package.manifest_path.parent().unwrap().join("src").into());
+            }
+        }
+    }
+
+    Ok(inputs)
 }
 
 async fn link(action: String) -> anyhow::Result<()> {
@@ -156,17 +592,53 @@ async fn link(action: String) -> anyhow::Result<()> {
             let args = args
                 .into_iter()
                 .skip(1)
-                .filter(|arg| arg != "-Wl,-dead_strip")
+                .filter(|arg| arg != "-Wl,-dead_strip" && arg != "-Wl,--gc-sections")
                 .collect::<Vec<String>>();
 
             let object_files: Vec<_> = args.iter().filter(|arg| arg.ends_with(".o")).collect();
             cache_incrementals(object_files.as_ref());
+            diff::save_baseline()?;
+
+            // Export the statics rustc kept private to a codegen unit, so patches can share them
+            let (exported, _) = strip::strip_objects(
+                &object_files,
+                &imports::SymbolProviders::default(),
+                &app_objects_dir(),
+            )?;
+            let exported = object_files
+                .iter()
+                .map(|arg| arg.as_str())
+                .zip(exported)
+                .collect::<HashMap<_, _>>();
+            let args = args
+                .iter()
+                .map(|arg| match exported.get(arg.as_str()) {
+                    Some(path) => path.to_string_lossy().to_string(),
+                    None => arg.clone(),
+                })
+                .collect::<Vec<_>>();
+
+            // ELF executables only put what something else links against in the dynamic symbol
+            // table, so ask for everything - patches bind to the app through it
+            let (format, _) = object_format(&object_files)?;
+            let export_all = match format {
+                BinaryFormat::Elf => Some("-Wl,--export-dynamic"),
+                _ => None,
+            };
 
             // Run ld with the args
-            let res = Command::new("cc").args(args).output().await?;
-            let err = String::from_utf8_lossy(&res.stderr);
+            let res = Command::new("cc")
+                .args(args)
+                .args(export_all)
+                .output()
+                .await?;
+            let err = symbol::demangle_text(&String::from_utf8_lossy(&res.stderr));
             std::fs::write(workspace_root().join("link_errs.txt"), &*err).unwrap();
 
+            if !res.status.success() {
+                anyhow::bail!("Linking failed: {err}");
+            }
+
             return Ok(());
         }
 
@@ -187,7 +659,15 @@ async fn link(action: String) -> anyhow::Result<()> {
                 .parse()
                 .unwrap();
 
-            diff::attempt_partial_link(main_ptr, patch_target, out_file.clone().into()).await;
+            _ = diff::attempt_partial_link(main_ptr, patch_target, out_file.clone().into()).await;
+
+            // Link against the statics and thread-locals of the app and the patches it has loaded,
+            // rather than fresh copies of them
+            let (object_files, shared_statics) = strip::strip_objects(
+                &object_files,
+                &imports::SymbolProviders::load(&providers_path())?,
+                &patch_objects_dir(),
+            )?;
 
             // -O0 ? supposedly faster
             // -reproducible - even better?
@@ -195,16 +675,12 @@ async fn link(action: String) -> anyhow::Result<()> {
             // -e symbol_name - for setting the entrypoint
             // -keep_relocs ?
 
-            // run the linker, but unexport the `_main` symbol
+            // run the linker, but unexport the `main` symbol
+            let (format, arch) = object_format(&object_files)?;
+            let flags = patch_link_flags(format, arch, &workspace_root().join("data"))?;
             let res = Command::new("cc")
                 .args(object_files)
-                .arg("-dylib")
-                .arg("-undefined")
-                .arg("dynamic_lookup")
-                .arg("-Wl,-unexported_symbol,_main")
-                .arg("-arch")
-                .arg("arm64")
-                .arg("-dead_strip") // maybe?
+                .args(flags)
                 .arg("-o")
                 .arg(&out_file)
                 .stdout(Stdio::piped())
@@ -212,8 +688,19 @@ async fn link(action: String) -> anyhow::Result<()> {
                 .output()
                 .await?;
 
-            let err = String::from_utf8_lossy(&res.stderr);
+            let err = symbol::demangle_text(&String::from_utf8_lossy(&res.stderr));
             std::fs::write(workspace_root().join("link_errs.txt"), &*err).unwrap();
+
+            // Hand the driver what went wrong along with the rest of the report
+            let mut report = report::ReloadReport::load(&report::report_path())?;
+            report.link_diagnostics = diagnostics::parse_link_errors(&err);
+            report.shared_statics = shared_statics;
+            report.save(&report::report_path())?;
+
+            // Fail the link so rustc fails and the driver knows to fall back to a full restart
+            if !res.status.success() {
+                anyhow::bail!("Linking the patch failed: {err}");
+            }
         }
 
         _ => panic!("don't know"),
@@ -222,6 +709,58 @@ async fn link(action: String) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// What the objects being linked were built for
+fn object_format(
+    object_files: &[impl AsRef<Path>],
+) -> anyhow::Result<(BinaryFormat, Architecture)> {
+    let first = object_files.first().context("No objects to link")?.as_ref();
+    let data = std::fs::read(first)?;
+    let file = object::File::parse(&*data)
+        .with_context(|| format!("Failed to parse {}", first.display()))?;
+    Ok((file.format(), file.architecture()))
+}
+
+/// How to link a patch into a library the app can load, leaving the app's symbols to be resolved
+/// when it's loaded. The patch never exports `main`, so the app's stays the one the loader finds.
+///
+/// ELF needs a version script to hide `main`, which goes in `scratch`.
+fn patch_link_flags(
+    format: BinaryFormat,
+    arch: Architecture,
+    scratch: &Path,
+) -> anyhow::Result<Vec<String>> {
+    match format {
+        BinaryFormat::Elf => {
+            let script = scratch.join("patch.ver");
+            std::fs::create_dir_all(scratch)?;
+            std::fs::write(&script, "{ global: *; local: main; };\n")?;
+            Ok(vec![
+                "-shared".to_string(),
+                format!("-Wl,--version-script={}", script.display()),
+            ])
+        }
+        BinaryFormat::MachO => {
+            let arch = match arch {
+                Architecture::Aarch64 => "arm64",
+                Architecture::X86_64 => "x86_64",
+                other => anyhow::bail!("Can't link a Mach-O patch for {other:?}"),
+            };
+            Ok([
+                "-dylib",
+                "-undefined",
+                "dynamic_lookup",
+                "-Wl,-unexported_symbol,_main",
+                "-arch",
+                arch,
+                "-dead_strip", // maybe?
+            ]
+            .map(str::to_string)
+            .to_vec())
+        }
+        other => anyhow::bail!("Can't link a patch for {other:?} objects"),
+    }
+}
+
 /// Move all previous object files to "incremental-old" and all new object files to "incremental-new"
 fn cache_incrementals(object_files: &[&String]) {
     let old = workspace_root().join("data").join("incremental-old");
@@ -251,11 +790,33 @@ fn workspace_root() -> PathBuf {
     "/Users/jonkelley/Development/Tinkering/ipbp".into()
 }
 
+/// Where the rustc wrapper drops the invocations it captures
+fn capture_dir() -> PathBuf {
+    workspace_root().join("data").join("rustc")
+}
+
 struct CargoOutputResult {
     output_location: Utf8PathBuf,
-    direct_rustc: Vec<String>,
 }
 
+/// A build that stopped on errors, along with what the compiler said about them
+#[derive(Debug)]
+struct BuildFailed {
+    diagnostics: Vec<String>,
+}
+
+impl std::fmt::Display for BuildFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Build failed")?;
+        for diagnostic in self.diagnostics.iter() {
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildFailed {}
+
 async fn run_cargo_output(
     mut child: Child,
     should_render: bool,
@@ -263,11 +824,10 @@ async fn run_cargo_output(
     let stdout = tokio::io::BufReader::new(child.stdout.take().unwrap());
     let stderr = tokio::io::BufReader::new(child.stderr.take().unwrap());
     let mut output_location = None;
+    let mut errors = vec![];
     let mut stdout = stdout.lines();
     let mut stderr = stderr.lines();
 
-    let mut direct_rustc = vec![];
-
     loop {
         use cargo_metadata::Message;
 
@@ -301,25 +861,19 @@ async fn run_cargo_output(
                             println!("rendered: {rendered}");
                         }
                     }
+                    errors.extend(rendered_error(compiler_message.message));
                 }
                 Message::BuildScriptExecuted(_build_script) => {}
                 Message::BuildFinished(build_finished) => {
                     if !build_finished.success {
                         // assuming we received a message from the compiler, so we can exit
-                        anyhow::bail!("Build failed");
+                        return Err(BuildFailed {
+                            diagnostics: errors,
+                        }
+                        .into());
                     }
                 }
                 Message::TextLine(word) => {
-                    if word.trim().starts_with("Running ") {
-                        // trim everyting but the contents between the quotes
-                        let args = word
-                            .trim()
-                            .trim_start_matches("Running `")
-                            .trim_end_matches('`');
-
-                        direct_rustc.extend(shell_words::split(args).unwrap());
-                    }
-
                     #[derive(Debug, Deserialize)]
                     struct RustcArtifact {
                         artifact: PathBuf,
@@ -333,6 +887,11 @@ async fn run_cargo_output(
                         }
                     }
 
+                    // rustc on its own emits bare diagnostics rather than cargo messages
+                    if let Ok(diagnostic) = serde_json::from_str::<Diagnostic>(&word) {
+                        errors.extend(rendered_error(diagnostic));
+                    }
+
                     if should_render {
                         println!("text: {word}")
                     }
@@ -342,11 +901,79 @@ async fn run_cargo_output(
         }
     }
 
-    let output_location =
-        output_location.context("Failed to find output location. Build must've failed.")?;
+    let output_location = match output_location {
+        Some(output_location) => output_location,
+        None if !errors.is_empty() => {
+            return Err(BuildFailed {
+                diagnostics: errors,
+            }
+            .into())
+        }
+        None => anyhow::bail!("Failed to find output location. Build must've failed."),
+    };
 
-    Ok(CargoOutputResult {
-        output_location,
-        direct_rustc,
-    })
+    Ok(CargoOutputResult { output_location })
+}
+
+/// The rendered text of an error, skipping warnings and notes
+fn rendered_error(diagnostic: Diagnostic) -> Option<String> {
+    match diagnostic.level {
+        DiagnosticLevel::Error | DiagnosticLevel::Ice => diagnostic.rendered,
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use object::{ObjectKind, ObjectSymbol, SymbolScope};
+
+    #[test]
+    fn links_elf_patches_into_shared_objects() {
+        let dir = std::env::temp_dir().join(format!("hotreload-link-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut patch = TestObject::elf();
+        patch.function("main", &[0xc3], SymbolScope::Dynamic);
+        patch.function("patched_fn", &[0xc3], SymbolScope::Dynamic);
+        patch.import("app_helper", false);
+        let object = dir.join("patch.o");
+        std::fs::write(&object, patch.write()).unwrap();
+
+        let (format, arch) = object_format(&[&object]).unwrap();
+        assert_eq!((format, arch), (BinaryFormat::Elf, Architecture::X86_64));
+
+        let out = dir.join("patch.so");
+        let res = std::process::Command::new("cc")
+            .arg(&object)
+            .args(patch_link_flags(format, arch, &dir).unwrap())
+            .arg("-o")
+            .arg(&out)
+            .output()
+            .unwrap();
+        assert!(
+            res.status.success(),
+            "{}",
+            String::from_utf8_lossy(&res.stderr)
+        );
+
+        // The app's symbols are left for the loader, and `main` stays the app's
+        let data = std::fs::read(&out).unwrap();
+        let linked = object::File::parse(&*data).unwrap();
+        assert_eq!(linked.kind(), ObjectKind::Dynamic);
+        let exports = linked
+            .exports()
+            .unwrap()
+            .iter()
+            .map(|e| String::from_utf8_lossy(e.name()).to_string())
+            .collect::<Vec<_>>();
+        assert!(exports.contains(&"patched_fn".to_string()));
+        assert!(!exports.contains(&"main".to_string()));
+        assert!(linked
+            .dynamic_symbols()
+            .any(|s| s.is_undefined() && s.name() == Ok("app_helper")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
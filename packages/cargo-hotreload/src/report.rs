@@ -0,0 +1,393 @@
+//! A structured summary of what changed between two builds
+//!
+//! The diff runs inside the linker process, so the report is saved to disk as JSON and picked up
+//! again by the driver which prints it and decides what to do with the patch.
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    path::PathBuf,
+};
+
+use crate::diagnostics::{LinkDiagnostic, Severity};
+use crate::diff::{
+    ChangeKind, DanglingReason, DanglingRef, SourceRange, SymbolChange, SymbolClass,
+};
+use crate::symbol::{demangle, is_main, SymbolFlavor, SymbolName};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ReloadReport {
+    pub functions_changed: Vec<String>,
+    pub functions_added: Vec<String>,
+    pub functions_removed: Vec<String>,
+    pub statics_added: Vec<String>,
+    pub statics_changed: Vec<String>,
+    pub statics_removed: Vec<String>,
+    pub constants_changed: Vec<String>,
+    pub tls_changed: Vec<String>,
+    pub vtables_changed: Vec<String>,
+    pub closures_changed: Vec<String>,
+
+    /// Only closures changed - the functions that own them are untouched
+    pub closure_only: bool,
+
+    /// Unchanged code that still references removed functions, as `(referrer, removed)`
+    pub dangling_references: Vec<(String, String)>,
+
+    /// Unchanged code that still calls functions whose signature changed, as `(caller, callee)`
+    #[serde(default)]
+    pub stale_callers: Vec<(String, String)>,
+
+    pub verdict: Verdict,
+
+    /// Where the changed symbols are in the source, by demangled name. Only filled in when the app
+    /// is built with line tables.
+    #[serde(default)]
+    pub source_ranges: BTreeMap<String, SourceRange>,
+
+    /// Registered hot functions whose code is affected by the patch, as passed to
+    /// `use_hotreload_component`
+    #[serde(default)]
+    pub hot_functions: Vec<String>,
+
+    /// What the linker complained about when linking the patch
+    #[serde(default)]
+    pub link_diagnostics: Vec<LinkDiagnostic>,
+
+    /// Statics and thread-locals the patch uses the app's copy of instead of its own
+    #[serde(default)]
+    pub shared_statics: Vec<String>,
+
+    /// Things that went wrong while diffing but didn't stop us from producing a patch
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Verdict {
+    #[default]
+    HotSafe,
+    RestartRequired {
+        reasons: Vec<String>,
+    },
+}
+
+impl ReloadReport {
+    pub fn new(changes: &HashMap<String, SymbolChange>, dangling: &[DanglingRef]) -> Self {
+        let mut report = Self::default();
+
+        for (name, change) in changes {
+            let symbol = SymbolName::new(name);
+            let pretty = symbol.to_string();
+
+            if symbol.flavor() == SymbolFlavor::Vtable {
+                report.vtables_changed.push(pretty);
+                continue;
+            }
+
+            match (change.class, change.kind) {
+                (SymbolClass::Function, ChangeKind::Modified) => {
+                    if symbol.is_closure() {
+                        report.closures_changed.push(pretty.clone());
+                    }
+                    report.functions_changed.push(pretty);
+                }
+                (SymbolClass::Function, ChangeKind::Added) => report.functions_added.push(pretty),
+                (SymbolClass::Function, ChangeKind::Removed) => {
+                    report.functions_removed.push(pretty)
+                }
+                (SymbolClass::Static, ChangeKind::Added) => report.statics_added.push(pretty),
+                (SymbolClass::Static, ChangeKind::Modified) => report.statics_changed.push(pretty),
+                (SymbolClass::Static, ChangeKind::Removed) => report.statics_removed.push(pretty),
+                (SymbolClass::Constant, ChangeKind::Modified) => {
+                    report.constants_changed.push(pretty)
+                }
+                (SymbolClass::ThreadLocal, ChangeKind::Added | ChangeKind::Modified) => {
+                    report.tls_changed.push(pretty)
+                }
+                _ => {}
+            }
+        }
+
+        for list in [
+            &mut report.functions_changed,
+            &mut report.functions_added,
+            &mut report.functions_removed,
+            &mut report.statics_added,
+            &mut report.statics_changed,
+            &mut report.statics_removed,
+            &mut report.constants_changed,
+            &mut report.tls_changed,
+            &mut report.vtables_changed,
+            &mut report.closures_changed,
+        ] {
+            list.sort();
+            list.dedup();
+        }
+
+        let dangling = |reason| {
+            dangling
+                .iter()
+                .filter(|d| d.reason == reason)
+                .map(|d| (demangle(&d.parent), demangle(&d.target)))
+                .sorted()
+                .dedup()
+                .collect()
+        };
+        report.dangling_references = dangling(DanglingReason::Removed);
+        report.stale_callers = dangling(DanglingReason::SignatureChanged);
+
+        report.closure_only = !report.closures_changed.is_empty()
+            && report.closures_changed.len() == report.functions_changed.len();
+
+        report.verdict = report.decide_verdict(changes);
+        report
+    }
+
+    fn decide_verdict(&self, changes: &HashMap<String, SymbolChange>) -> Verdict {
+        let mut reasons = vec![];
+
+        // The entrypoint has already run - patching it does nothing
+        if changes
+            .iter()
+            .any(|(name, c)| is_main(name) && c.kind == ChangeKind::Modified)
+        {
+            reasons.push("`main` changed".to_string());
+        }
+
+        // The running process already has its own copy of these initialized
+        for s in self.statics_changed.iter() {
+            reasons.push(format!("initializer of static `{s}` changed"));
+        }
+
+        for s in self.tls_changed.iter() {
+            reasons.push(format!("thread-local `{s}` changed"));
+        }
+
+        // The old copy of the referrer stays live and keeps calling the old function
+        for (parent, removed) in self.dangling_references.iter() {
+            reasons.push(format!("`{parent}` still references removed `{removed}`"));
+        }
+        for (caller, callee) in self.stale_callers.iter() {
+            reasons.push(format!(
+                "`{caller}` still calls `{callee}` with its old signature"
+            ));
+        }
+
+        match reasons.is_empty() {
+            true => Verdict::HotSafe,
+            false => Verdict::RestartRequired { reasons },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions_changed.is_empty()
+            && self.functions_added.is_empty()
+            && self.functions_removed.is_empty()
+            && self.statics_added.is_empty()
+            && self.statics_changed.is_empty()
+            && self.statics_removed.is_empty()
+            && self.constants_changed.is_empty()
+            && self.tls_changed.is_empty()
+            && self.vtables_changed.is_empty()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Print the report to the terminal
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("No changes detected");
+        }
+
+        let sections = [
+            ("changed", &self.functions_changed),
+            ("added", &self.functions_added),
+            ("removed", &self.functions_removed),
+            ("static added", &self.statics_added),
+            ("static changed", &self.statics_changed),
+            ("static removed", &self.statics_removed),
+            ("const changed", &self.constants_changed),
+            ("tls changed", &self.tls_changed),
+            ("vtable changed", &self.vtables_changed),
+        ];
+
+        for (label, list) in sections {
+            for sym in list {
+                match self.source_ranges.get(sym) {
+                    Some(range) => println!("  {label}: {sym} ({range})"),
+                    None => println!("  {label}: {sym}"),
+                }
+            }
+        }
+
+        if self.closure_only {
+            println!("  (only closures changed)");
+        }
+
+        for f in self.hot_functions.iter() {
+            println!("  rerun: {}", demangle(f));
+        }
+
+        for s in self.shared_statics.iter() {
+            println!("  shared with the app: {}", demangle(s));
+        }
+
+        for w in self.warnings.iter() {
+            println!("⚠️ {w}");
+        }
+
+        for d in self.link_diagnostics.iter() {
+            match d.severity {
+                Severity::Error => println!("🔗 {d}"),
+                Severity::Warning => println!("⚠️ {d}"),
+                Severity::Note => println!("   {d}"),
+            }
+        }
+
+        match &self.verdict {
+            Verdict::HotSafe => println!("✅ hot-safe"),
+            Verdict::RestartRequired { reasons } => {
+                println!("🔄 restart required:");
+                for r in reasons {
+                    println!("  - {r}");
+                }
+            }
+        }
+    }
+}
+
+/// What the driver sends the app for each patch, one JSON object per line on its stdin
+#[derive(Debug, Serialize)]
+pub struct PatchMessage<'a> {
+    /// The library holding the new code
+    pub library: &'a str,
+
+    /// The hot functions to re-run - everything else keeps rendering what it already has
+    pub affected: &'a [String],
+
+    /// The patch's objects, for the app to load itself rather than the library
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub objects: &'a [String],
+}
+
+/// The app's answer to a [`PatchMessage`], one JSON object per line on its stdout behind
+/// [`PatchAck::PREFIX`]
+#[derive(Debug, Deserialize)]
+pub struct PatchAck {
+    /// The library the app was sent
+    pub library: PathBuf,
+
+    /// Why the app couldn't load the patch. It keeps running its old code when this is set.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl PatchAck {
+    pub const PREFIX: &'static str = "[hotreload] ";
+
+    /// Pick an ack out of a line of the app's output
+    pub fn parse(line: &str) -> Option<Self> {
+        serde_json::from_str(line.strip_prefix(Self::PREFIX)?).ok()
+    }
+}
+
+/// Where the linker drops the report for the driver to pick up
+pub fn report_path() -> PathBuf {
+    crate::workspace_root().join("reload_report.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(kind: ChangeKind, class: SymbolClass) -> SymbolChange {
+        SymbolChange { kind, class }
+    }
+
+    #[test]
+    fn classifies_changes() {
+        let changes = HashMap::from([
+            (
+                "__ZN7harness13zoom_controls17h0123456789abcdefE".to_string(),
+                change(ChangeKind::Modified, SymbolClass::Function),
+            ),
+            (
+                "__ZN7harness6NewKid17h0123456789abcdefE".to_string(),
+                change(ChangeKind::Added, SymbolClass::Function),
+            ),
+        ]);
+
+        let report = ReloadReport::new(&changes, &[]);
+        assert_eq!(report.functions_changed, vec!["harness::zoom_controls"]);
+        assert_eq!(report.functions_added, vec!["harness::NewKid"]);
+        assert_eq!(report.verdict, Verdict::HotSafe);
+    }
+
+    #[test]
+    fn main_and_statics_require_restart() {
+        let changes = HashMap::from([
+            (
+                "_ZN7harness4main17h5c3f0a9e1d2b4c6aE".to_string(),
+                change(ChangeKind::Modified, SymbolClass::Function),
+            ),
+            (
+                "__ZN7harness8MyGlobal17h0123456789abcdefE".to_string(),
+                change(ChangeKind::Modified, SymbolClass::Static),
+            ),
+        ]);
+
+        let report = ReloadReport::new(&changes, &[]);
+        let Verdict::RestartRequired { reasons } = report.verdict else {
+            panic!("expected a restart");
+        };
+        assert_eq!(reasons.len(), 2);
+    }
+
+    #[test]
+    fn dangling_references_require_restart() {
+        let changes = HashMap::from([(
+            "__ZN7harness6helper17h0123456789abcdefE".to_string(),
+            change(ChangeKind::Removed, SymbolClass::Function),
+        )]);
+        let dangling = [DanglingRef {
+            parent: "__ZN7harness4tick17h0123456789abcdefE".to_string(),
+            target: "__ZN7harness6helper17h0123456789abcdefE".to_string(),
+            reason: DanglingReason::Removed,
+        }];
+
+        let report = ReloadReport::new(&changes, &dangling);
+        assert_eq!(report.functions_removed, vec!["harness::helper"]);
+        assert_eq!(
+            report.verdict,
+            Verdict::RestartRequired {
+                reasons: vec![
+                    "`harness::tick` still references removed `harness::helper`".to_string()
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn picks_acks_out_of_the_apps_output() {
+        let ack = PatchAck::parse(r#"[hotreload] {"library":"/tmp/output-1","error":null}"#);
+        assert_eq!(ack.unwrap().library, Path::new("/tmp/output-1"));
+
+        let ack = PatchAck::parse(r#"[hotreload] {"library":"/tmp/output-2","error":"bad"}"#);
+        assert_eq!(ack.unwrap().error.as_deref(), Some("bad"));
+
+        assert!(PatchAck::parse("hello from the app").is_none());
+        assert!(PatchAck::parse("[hotreload] not json").is_none());
+    }
+}
@@ -0,0 +1,372 @@
+//! Rewriting patch objects so they share the running binary's statics
+//!
+//! Every object rustc emits carries its own definitions of the statics and thread-locals it uses,
+//! so a patch linked from them would start out with fresh copies instead of the app's state.
+//! Definitions the app or an earlier patch already exports are turned into undefined references
+//! here, leaving the dynamic loader to bind them to the existing copy. Statics the patch introduces
+//! are kept.
+//!
+//! Statics private to a codegen unit never make it into an export table, so the same rewrite also
+//! makes them global - for the app's objects before its first link, and for each patch's new
+//! statics so later patches can bind to them in turn.
+
+use anyhow::{Context, Result};
+use object::{
+    write, Object, ObjectComdat, ObjectSection, ObjectSymbol, RelocationTarget, SectionIndex,
+    SectionKind, SymbolFlags, SymbolKind, SymbolScope, SymbolSection,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{imports::SymbolProviders, symbol::SymbolName};
+
+/// `.llvm_addrsig` refers to symbols by index, which doesn't survive the rewrite
+const SHT_LLVM_ADDRSIG: u32 = 0x6fff_4c03;
+
+/// Strip every object in `objects` into `out_dir`, returning the rewritten objects and the
+/// statics they now take from the app
+pub fn strip_objects(
+    objects: &[&String],
+    existing: &SymbolProviders,
+    out_dir: &Path,
+) -> Result<(Vec<PathBuf>, Vec<String>)> {
+    _ = std::fs::remove_dir_all(out_dir);
+    std::fs::create_dir_all(out_dir)?;
+
+    let mut stripped = vec![];
+    let mut shared = vec![];
+    for path in objects.iter() {
+        let path = Path::new(path.as_str());
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let (out, names) = strip_existing(&file, existing)
+            .with_context(|| format!("Failed to strip statics from {}", path.display()))?;
+
+        let out_path = out_dir.join(path.file_name().unwrap());
+        std::fs::write(&out_path, out)?;
+        stripped.push(out_path);
+        shared.extend(names);
+    }
+
+    shared.sort();
+    shared.dedup();
+    Ok((stripped, shared))
+}
+
+/// Rewrite an object with the statics and thread-locals in `existing` turned into imports, and
+/// the rest of its statics exported
+pub fn strip_existing(
+    in_object: &object::File,
+    existing: &SymbolProviders,
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut out_object = write::Object::new(
+        in_object.format(),
+        in_object.architecture(),
+        in_object.endianness(),
+    );
+    out_object.mangling = write::Mangling::None;
+    out_object.flags = in_object.flags();
+
+    let mut out_sections = HashMap::new();
+    for in_section in in_object.sections() {
+        if matches!(
+            in_section.kind(),
+            SectionKind::Metadata | SectionKind::Elf(SHT_LLVM_ADDRSIG)
+        ) {
+            continue;
+        }
+
+        let section_id = out_object.add_section(
+            in_section.segment_name()?.unwrap_or("").as_bytes().to_vec(),
+            in_section.name()?.as_bytes().to_vec(),
+            in_section.kind(),
+        );
+        let out_section = out_object.section_mut(section_id);
+        if out_section.is_bss() {
+            out_section.append_bss(in_section.size(), in_section.align());
+        } else {
+            out_section.set_data(in_section.data()?, in_section.align());
+        }
+        out_section.flags = in_section.flags();
+        out_sections.insert(in_section.index(), section_id);
+    }
+
+    let mut out_symbols = HashMap::new();
+    let mut imported = HashMap::<SectionIndex, write::SymbolId>::new();
+    let mut shared = vec![];
+    for in_symbol in in_object.symbols() {
+        let name = in_symbol.name().unwrap_or("");
+
+        // The app has this one - reference its copy instead of defining another
+        if should_strip(&in_symbol, name, existing) {
+            let symbol_id = out_object.add_symbol(write::Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 0,
+                kind: in_symbol.kind(),
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: write::SymbolSection::Undefined,
+                flags: SymbolFlags::None,
+            });
+            out_symbols.insert(in_symbol.index(), symbol_id);
+            shared.push(name.to_string());
+
+            // With one static per section, references through the section are references to it
+            if let Some(index) = in_symbol.section_index() {
+                let section = in_object.section_by_index(index)?;
+                if in_symbol.address() == section.address() && in_symbol.size() == section.size() {
+                    imported.insert(index, symbol_id);
+                }
+            }
+            continue;
+        }
+
+        let (section, value) = match in_symbol.section() {
+            SymbolSection::None => (write::SymbolSection::None, in_symbol.address()),
+            SymbolSection::Undefined => (write::SymbolSection::Undefined, in_symbol.address()),
+            SymbolSection::Absolute => (write::SymbolSection::Absolute, in_symbol.address()),
+            SymbolSection::Common => (write::SymbolSection::Common, in_symbol.address()),
+            SymbolSection::Section(index) => match out_sections.get(&index) {
+                Some(out_section) => (
+                    write::SymbolSection::Section(*out_section),
+                    in_symbol.address() - in_object.section_by_index(index)?.address(),
+                ),
+
+                // Only section symbols point at the sections we skipped
+                None => continue,
+            },
+            _ => anyhow::bail!("unknown symbol section for {in_symbol:?}"),
+        };
+
+        // Let the writer pick the binding and visibility for the new scope
+        let scope = match is_private_static(&in_symbol, name) {
+            true => SymbolScope::Dynamic,
+            false => in_symbol.scope(),
+        };
+        let flags = match in_symbol.flags() {
+            _ if scope != in_symbol.scope() => SymbolFlags::None,
+            SymbolFlags::None => SymbolFlags::None,
+            SymbolFlags::Elf { st_info, st_other } => SymbolFlags::Elf { st_info, st_other },
+            SymbolFlags::MachO { n_desc } => SymbolFlags::MachO { n_desc },
+            _ => anyhow::bail!("unsupported symbol flags for {in_symbol:?}"),
+        };
+
+        let symbol_id = out_object.add_symbol(write::Symbol {
+            name: name.as_bytes().to_vec(),
+            value,
+            size: in_symbol.size(),
+            kind: in_symbol.kind(),
+            scope,
+            weak: in_symbol.is_weak(),
+            section,
+            flags,
+        });
+        out_symbols.insert(in_symbol.index(), symbol_id);
+    }
+
+    for in_section in in_object.sections() {
+        let Some(&out_section) = out_sections.get(&in_section.index()) else {
+            continue;
+        };
+
+        for (offset, in_relocation) in in_section.relocations() {
+            let section_target = |section: SectionIndex, out_object: &mut write::Object| {
+                match imported.get(&section) {
+                    Some(symbol) => Ok(*symbol),
+                    None => out_sections
+                        .get(&section)
+                        .map(|out| out_object.section_symbol(*out))
+                        .context("relocation against a section that was dropped"),
+                }
+            };
+
+            let symbol = match in_relocation.target() {
+                RelocationTarget::Symbol(symbol) => {
+                    let in_symbol = in_object.symbol_by_index(symbol)?;
+                    match (in_symbol.kind(), in_symbol.section_index()) {
+                        (SymbolKind::Section, Some(section)) => {
+                            section_target(section, &mut out_object)?
+                        }
+                        _ => *out_symbols
+                            .get(&symbol)
+                            .context("relocation against a symbol that was dropped")?,
+                    }
+                }
+                RelocationTarget::Section(section) => section_target(section, &mut out_object)?,
+                _ => anyhow::bail!("unknown relocation target for {in_relocation:?}"),
+            };
+
+            out_object.add_relocation(
+                out_section,
+                write::Relocation {
+                    offset,
+                    symbol,
+                    addend: in_relocation.addend(),
+                    flags: in_relocation.flags(),
+                },
+            )?;
+        }
+    }
+
+    for in_comdat in in_object.comdats() {
+        let sections = in_comdat
+            .sections()
+            .filter_map(|section| out_sections.get(&section).copied())
+            .collect();
+        let Some(&symbol) = out_symbols.get(&in_comdat.symbol()) else {
+            continue;
+        };
+        out_object.add_comdat(write::Comdat {
+            kind: in_comdat.kind(),
+            symbol,
+            sections,
+        });
+    }
+
+    if let object::File::MachO64(file) = in_object {
+        if let Some(in_build_version) = file.build_version()? {
+            let endian = in_object.endianness();
+            let mut out_build_version = write::MachOBuildVersion::default();
+            out_build_version.platform = in_build_version.platform.get(endian);
+            out_build_version.minos = in_build_version.minos.get(endian);
+            out_build_version.sdk = in_build_version.sdk.get(endian);
+            out_object.set_macho_build_version(out_build_version);
+        }
+    }
+
+    Ok((out_object.write()?, shared))
+}
+
+fn should_strip(symbol: &object::Symbol, name: &str, existing: &SymbolProviders) -> bool {
+    !symbol.is_undefined()
+        && !symbol.is_common()
+        && (symbol.is_global() || is_private_static(symbol, name))
+        && symbol.kind() != SymbolKind::Text
+        && existing.provides(name)
+}
+
+/// A static or thread-local nothing outside its object can link against yet. Only Rust names are
+/// unique enough to export - LLVM's labels and C statics can repeat from one object to the next.
+fn is_private_static(symbol: &object::Symbol, name: &str) -> bool {
+    !symbol.is_undefined()
+        && !symbol.is_common()
+        && matches!(symbol.kind(), SymbolKind::Data | SymbolKind::Tls)
+        && symbol.scope() != SymbolScope::Dynamic
+        && SymbolName::new(name).is_rust()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TestObject;
+    use object::elf;
+
+    #[test]
+    fn imports_statics_the_app_already_has() {
+        let mut obj = TestObject::elf();
+
+        #[rustfmt::skip]
+        let code = [
+            0x8b, 0x05, 0, 0, 0, 0, // mov eax, [rip + OLD_COUNTER]
+            0x03, 0x05, 0, 0, 0, 0, // add eax, [rip + OLD_TOTAL]
+            0x03, 0x05, 0, 0, 0, 0, // add eax, [rip + NEW_COUNTER]
+            0xc3,                   // ret
+        ];
+        let (_, text) = obj.function("read_counters", &code, SymbolScope::Dynamic);
+
+        let mut add_static = |name: &str| {
+            obj.static_item(name, SectionKind::Data, &[1, 0, 0, 0], SymbolScope::Linkage)
+        };
+        let (by_name, _) = add_static("OLD_COUNTER");
+        let (_, old_total) = add_static("OLD_TOTAL");
+        let (_, new_counter) = add_static("NEW_COUNTER");
+
+        // One reference by name, the others through the section like LLVM does for locals
+        let old_total = obj.section_symbol(old_total);
+        let new_counter = obj.section_symbol(new_counter);
+        for (offset, symbol) in [(2, by_name), (8, old_total), (14, new_counter)] {
+            obj.relocate(text, offset, symbol, -4, elf::R_X86_64_PC32);
+        }
+
+        let data = obj.write();
+        let file = object::File::parse(&*data).unwrap();
+        let existing = SymbolProviders::from_iter(["OLD_COUNTER", "OLD_TOTAL"]);
+        let (out, shared) = strip_existing(&file, &existing).unwrap();
+        assert_eq!(shared, ["OLD_COUNTER", "OLD_TOTAL"]);
+
+        let out = object::File::parse(&*out).unwrap();
+        let old = out.symbol_by_name("OLD_COUNTER").unwrap();
+        assert!(old.is_undefined() && old.is_global());
+        assert!(out.symbol_by_name("NEW_COUNTER").unwrap().is_definition());
+
+        let text = out.section_by_name(".text.read_counters").unwrap();
+        let targets = text
+            .relocations()
+            .map(|(_, reloc)| match reloc.target() {
+                RelocationTarget::Symbol(index) => {
+                    let sym = out.symbol_by_index(index).unwrap();
+                    (sym.is_undefined(), sym.name().unwrap().to_string())
+                }
+                other => panic!("unexpected target {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(targets[0], (true, "OLD_COUNTER".to_string()));
+        assert_eq!(targets[1], (true, "OLD_TOTAL".to_string()));
+        assert!(!targets[2].0);
+    }
+
+    #[test]
+    fn shares_and_exports_private_statics() {
+        let mut obj = TestObject::elf();
+        let (_, text) = obj.function(
+            "read_cache",
+            &[0x8b, 0x05, 0, 0, 0, 0, 0xc3],
+            SymbolScope::Linkage,
+        );
+
+        let mut add_local = |name: &str| {
+            let kind = SectionKind::UninitializedData;
+            obj.static_item(name, kind, &[0; 8], SymbolScope::Compilation)
+                .1
+        };
+        let cache = add_local("_ZN7harness5CACHE17h0123456789abcdefE");
+        add_local("_ZN7harness5FRESH17h0123456789abcdefE");
+        add_local("counter.0");
+
+        let cache = obj.section_symbol(cache);
+        obj.relocate(text, 2, cache, -4, elf::R_X86_64_PC32);
+
+        let data = obj.write();
+        let file = object::File::parse(&*data).unwrap();
+        let existing = SymbolProviders::from_iter(["_ZN7harness5CACHE17h0123456789abcdefE"]);
+        let (out, shared) = strip_existing(&file, &existing).unwrap();
+        assert_eq!(shared, ["_ZN7harness5CACHE17h0123456789abcdefE"]);
+
+        // The app's copy is used, the patch's own static is exported for the next patch, and names
+        // that could repeat across objects stay private
+        let out = object::File::parse(&*out).unwrap();
+        let cache = out
+            .symbol_by_name("_ZN7harness5CACHE17h0123456789abcdefE")
+            .unwrap();
+        assert!(cache.is_undefined() && cache.is_global());
+        let fresh = out
+            .symbol_by_name("_ZN7harness5FRESH17h0123456789abcdefE")
+            .unwrap();
+        assert!(fresh.is_definition() && fresh.scope() == SymbolScope::Dynamic);
+        let counter = out.symbol_by_name("counter.0").unwrap();
+        assert!(counter.is_local());
+
+        let (_, reloc) = out
+            .section_by_name(".text.read_cache")
+            .unwrap()
+            .relocations()
+            .next()
+            .unwrap();
+        assert_eq!(reloc.target(), RelocationTarget::Symbol(cache.index()));
+    }
+}
@@ -0,0 +1,210 @@
+//! How symbol names are shown to people
+//!
+//! Everything the driver prints, logs or saves goes through here so a symbol looks the same
+//! wherever it shows up. Both the legacy (`_ZN...17h<hash>E`) and v0 (`_R...`) manglings are
+//! handled, with or without Mach-O's extra leading underscore.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// A raw symbol name from an object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolName<'a> {
+    raw: &'a str,
+}
+
+/// What the compiler generated a symbol for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolFlavor {
+    /// Something written in the source
+    Plain,
+    Closure,
+
+    /// Vtable and reify shims that forward to the real function
+    Shim,
+
+    /// `core::ptr::drop_in_place::<T>`
+    DropGlue,
+    Vtable,
+}
+
+impl<'a> SymbolName<'a> {
+    pub fn new(raw: &'a str) -> Self {
+        Self { raw }
+    }
+
+    /// The demangled path with the hash suffix kept. Non-Rust names come back as they are.
+    pub fn with_hash(&self) -> String {
+        rustc_demangle::demangle(self.raw).to_string()
+    }
+
+    pub fn flavor(&self) -> SymbolFlavor {
+        let pretty = self.with_hash();
+
+        if pretty.contains("{{vtable.shim}}")
+            || pretty.contains("{{reify.shim}}")
+            || pretty.contains("{shim:")
+        {
+            SymbolFlavor::Shim
+        } else if pretty.starts_with("core::ptr::drop_in_place") {
+            SymbolFlavor::DropGlue
+        } else if self.is_closure() {
+            SymbolFlavor::Closure
+        } else if pretty.contains("{vtable") || self.is_vtable_data() {
+            SymbolFlavor::Vtable
+        } else {
+            SymbolFlavor::Plain
+        }
+    }
+
+    /// The data rustc emits for a vtable, labelled `vtable.<n>` behind whatever prefix the target
+    /// uses for assembler-local names. Vtables from older compilers are plain `.L__unnamed_<n>`
+    /// constants, which nothing about the name sets apart from any other constant.
+    fn is_vtable_data(&self) -> bool {
+        let label = [".L", "l_", "L", "_"]
+            .iter()
+            .find_map(|prefix| self.raw.strip_prefix(prefix))
+            .unwrap_or(self.raw);
+
+        label
+            .strip_prefix("vtable.")
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    }
+
+    /// Whether rustc mangled this name. Those carry a hash, so unlike C names or LLVM's labels they
+    /// mean the same thing in every object.
+    pub fn is_rust(&self) -> bool {
+        rustc_demangle::try_demangle(self.raw).is_ok()
+    }
+
+    /// Closures along with anything generated for them, like their shims
+    pub fn is_closure(&self) -> bool {
+        let pretty = self.with_hash();
+        pretty.contains("{{closure}}") || pretty.contains("{closure#")
+    }
+
+    /// The user's `fn main` at the root of the bin crate, or the C `main` that calls it
+    pub fn is_main(&self) -> bool {
+        if self.raw == "main" || self.raw == "_main" {
+            return true;
+        }
+
+        // Only a crate root's `main` - the hash is dropped so both manglings look alike
+        let Ok(demangled) = rustc_demangle::try_demangle(self.raw) else {
+            return false;
+        };
+        let path = format!("{demangled:#}");
+        matches!(path.split("::").collect::<Vec<_>>()[..], [_, "main"])
+    }
+}
+
+/// The demangled path without the hash suffix
+impl Display for SymbolName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", rustc_demangle::demangle(self.raw))
+    }
+}
+
+/// Demangle a symbol, dropping the hash suffix
+pub fn demangle(name: &str) -> String {
+    SymbolName::new(name).to_string()
+}
+
+pub fn is_main(name: &str) -> bool {
+    SymbolName::new(name).is_main()
+}
+
+/// Demangle every symbol mentioned in a blob of text, like linker errors
+pub fn demangle_text(text: &str) -> String {
+    let is_symbol_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$');
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(is_symbol_char) {
+        let (before, from) = rest.split_at(start);
+        let end = from.find(|c| !is_symbol_char(c)).unwrap_or(from.len());
+        let (word, after) = from.split_at(end);
+
+        out.push_str(before);
+        match rustc_demangle::try_demangle(word) {
+            Ok(demangled) => out.push_str(&format!("{demangled:#}")),
+            Err(_) => out.push_str(word),
+        }
+        rest = after;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangles_both_schemes() {
+        let legacy = SymbolName::new("__ZN7harness13zoom_controls17h0123456789abcdefE");
+        assert_eq!(legacy.to_string(), "harness::zoom_controls");
+        assert_eq!(
+            legacy.with_hash(),
+            "harness::zoom_controls::h0123456789abcdef"
+        );
+        assert_eq!(legacy.flavor(), SymbolFlavor::Plain);
+
+        let v0 = SymbolName::new("_RNCNvCs1234_7harness13zoom_controls0B3_");
+        assert_eq!(v0.to_string(), "harness::zoom_controls::{closure#0}");
+        assert_eq!(v0.flavor(), SymbolFlavor::Closure);
+
+        let glue = SymbolName::new("_ZN4core3ptr23drop_in_place$LT$u8$GT$17h0123456789abcdefE");
+        assert_eq!(glue.flavor(), SymbolFlavor::DropGlue);
+
+        assert_eq!(SymbolName::new("ltmp0").to_string(), "ltmp0");
+    }
+
+    #[test]
+    fn only_vtable_labels_are_vtables() {
+        for label in ["vtable.0", ".Lvtable.12", "l_vtable.3"] {
+            assert_eq!(
+                SymbolName::new(label).flavor(),
+                SymbolFlavor::Vtable,
+                "{label}"
+            );
+        }
+        for name in [
+            "build_vtable",
+            "_ZN7harness12build_vtable17h0123456789abcdefE",
+            "vtable.cache",
+            "my_vtable.0",
+        ] {
+            assert_eq!(
+                SymbolName::new(name).flavor(),
+                SymbolFlavor::Plain,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_bin_crates_main() {
+        assert!(is_main("_ZN7harness4main17h5c3f0a9e1d2b4c6aE"));
+        assert!(is_main("__ZN7harness4main17h5c3f0a9e1d2b4c6aE"));
+        assert!(is_main("_RNvCsdRvhTFJuWVU_7harness4main"));
+        assert!(is_main("_main"));
+
+        assert!(!is_main("_ZN7harness3app4main17h5c3f0a9e1d2b4c6aE"));
+        assert!(!is_main("_ZN7harness8mainline17h5c3f0a9e1d2b4c6aE"));
+        assert!(!is_main("mainline"));
+    }
+
+    #[test]
+    fn demangles_symbols_inside_text() {
+        let err = "Undefined symbols for architecture arm64:\n  \
+                   \"__ZN7harness6helper17h0123456789abcdefE\", referenced from: _main";
+        assert_eq!(
+            demangle_text(err),
+            "Undefined symbols for architecture arm64:\n  \
+             \"harness::helper\", referenced from: _main"
+        );
+    }
+}